@@ -0,0 +1,525 @@
+/** The actual compiler: turns a MidiTrack into a bash script of `beep` calls.
+ *
+ * Each note becomes a line (or several, while it's bent or arpeggiated) of the form
+ *
+ *     beep -f <hz> -l <length in ms> -D <delay in ms>
+ *
+ * where `-D` is the silence after the note before the next one begins. The UNIX beep utility is
+ * documented at http://www.johnath.com/beep/
+ */
+
+use std::cmp::{max, min};
+use std::vec::from_elem;
+use midi::MidiTrack;
+use midi::channels::ChannelTracker;
+use midi::notes::{Note, Pedals, track_notes_with_pedals, Stack};
+use midi::timing::TempoMap;
+use reduce::{Strategy, reduce, chords};
+use tuning::Tuning;
+#[cfg(test)]
+use midi::{parse_file, Division, TicksPerQuarter, Smpte, NoteOn, NoteOff, PitchWheel, ControlChange};
+#[cfg(test)]
+use midi::notes::{NO_PEDALS, ALL_PEDALS};
+#[cfg(test)]
+use reduce::{Highest, MostRecent};
+
+/// What to do when several notes sound at once.
+#[deriving(Clone, Eq)]
+pub enum Voicing {
+    /// Play just one of them, picked by the Strategy.
+    Reduced(Strategy),
+    /// Cycle through all of them from the bottom up, spending this many milliseconds on each, the
+    /// way old sound chips fake chords.
+    Arpeggiated(u32)
+}
+
+/// Everything about how a track gets turned into beeps.
+#[deriving(Clone)]
+pub struct Settings {
+    voicing : Voicing,
+    tuning : Tuning,
+    /// While the pitch wheel is moving, notes are cut into pieces at least this long, each held at
+    /// one pitch.
+    bend_step_ms : u32,
+    /// Which pedals get to hold notes on.
+    pedals : Pedals
+}
+
+/// A single line of a beep script.
+pub struct Beep {
+    key : u8,
+    frequency : f64,
+    length_ms : u32,
+    delay_ms : u32
+}
+
+/// Everything needed to write out one script: silence before the first note, then the beeps.
+pub struct BeepScript {
+    lead_in_ms : u32,
+    beeps : ~[Beep]
+}
+
+/// A beep placed on the timeline, in milliseconds from the start of the track.
+struct Span {
+    key : u8,
+    frequency : f64,
+    start_ms : u32,
+    end_ms : u32
+}
+
+/// From `ms` on, notes on a channel are bent this many semitones.
+#[deriving(Clone)]
+struct BendChange {
+    ms : u32,
+    semitones : f64
+}
+
+/// Compiles a track into a BeepScript. The PC speaker can only play one pitch at a time, so when
+/// notes overlap, the voicing in `settings` decides what's heard. Keys the tuning leaves silent
+/// become rests. Ticks are turned into milliseconds by `tempo`, which for a format 0 or 1 file
+/// should be the whole file's TempoMap, since tempo changes are usually in a different track from
+/// the notes.
+pub fn compile_track(track : &MidiTrack, tempo : &TempoMap, settings : &Settings) -> BeepScript {
+    let notes = track_notes_with_pedals(track, Stack, settings.pedals).all();
+    let bends = bend_changes(track, tempo);
+    let mut spans = ~[];
+    match settings.voicing {
+        Reduced(strategy) => {
+            for segment in reduce(notes, strategy).iter() {
+                match segment.note {
+                    Some(note) => {
+                        push_note(&mut spans, settings, bends[note.channel].as_slice(), note.key,
+                                  ticks_to_ms(segment.start, tempo), ticks_to_ms(segment.end, tempo));
+                    }
+                    None => {}
+                }
+            }
+        }
+        Arpeggiated(tone_ms) => {
+            for chord in chords(notes).iter() {
+                arpeggiate(&mut spans, settings, bends, chord.notes, tone_ms,
+                           ticks_to_ms(chord.start, tempo), ticks_to_ms(chord.end, tempo));
+            }
+        }
+    }
+    script_from_spans(spans)
+}
+
+/// Takes turns between `notes` from `start_ms` to `end_ms`, `tone_ms` at a time, lowest first. The
+/// last turn is cut short if the chord ends partway through it.
+fn arpeggiate(spans : &mut ~[Span], settings : &Settings, bends : &[~[BendChange]], notes : &[Note],
+              tone_ms : u32, start_ms : u32, end_ms : u32) {
+    // The same key on two channels is only played once.
+    let mut voices : ~[(u8, u8)] = ~[];
+    for note in notes.iter() {
+        if settings.tuning.frequency(note.key).is_some() && !voices.iter().any(|&(k, _)| k == note.key) {
+            voices.push((note.key, note.channel));
+        }
+    }
+    voices.sort();
+
+    let mut now = start_ms;
+    let mut turn = 0u;
+    while !voices.is_empty() && now < end_ms {
+        let (key, channel) = voices[turn % voices.len()];
+        let next = if voices.len() == 1 || end_ms - now < tone_ms { end_ms } else { now + tone_ms };
+        push_note(spans, settings, bends[channel].as_slice(), key, now, next);
+        now = next;
+        turn += 1;
+    }
+}
+
+/// Adds a note to `spans`, bent by whatever its channel's pitch wheel is doing. While the wheel
+/// holds still the note stays in one span. While it moves, the note is cut up wherever it does,
+/// but into pieces no shorter than `bend_step_ms`, each held at the bend it starts with.
+fn push_note(spans : &mut ~[Span], settings : &Settings, bends : &[BendChange], key : u8,
+             start_ms : u32, end_ms : u32) {
+    let base = match settings.tuning.frequency(key) {
+        Some(f) => f,
+        None => { return; }
+    };
+    let first = spans.len();
+    let mut now = start_ms;
+    while now < end_ms {
+        let (semitones, next_change) = bend_at(bends, now);
+        let next = match next_change {
+            Some(ms) => min(end_ms, max(ms, now + max(settings.bend_step_ms, 1))),
+            None => end_ms
+        };
+        let frequency = base * 2f64.powf(&(semitones / 12.0));
+        if spans.len() > first && spans[spans.len() - 1].frequency == frequency {
+            let last = spans.len() - 1;
+            spans[last].end_ms = next;
+        } else {
+            spans.push(Span { key : key, frequency : frequency, start_ms : now, end_ms : next });
+        }
+        now = next;
+    }
+}
+
+/// The bend in effect at `ms`, and when it next changes.
+fn bend_at(bends : &[BendChange], ms : u32) -> (f64, Option<u32>) {
+    // The first change after `ms`.
+    let mut low = 0u;
+    let mut high = bends.len();
+    while low < high {
+        let middle = (low + high) / 2;
+        if bends[middle].ms <= ms {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    let semitones = if low == 0 { 0.0 } else { bends[low - 1].semitones };
+    let next = if low < bends.len() { Some(bends[low].ms) } else { None };
+    (semitones, next)
+}
+
+/// How far each channel is bent over the course of the track, indexed by channel. The bend is the
+/// pitch wheel times the channel's bend range, which is 2 semitones until RPN 0,0 (CC 101 and
+/// 100, then 6 for semitones and 38 for cents) says otherwise.
+fn bend_changes(track : &MidiTrack, tempo : &TempoMap) -> ~[~[BendChange]] {
+    let mut changes = from_elem(16, ~[]);
+    let mut tracker = ChannelTracker::new();
+    let mut now = 0u64;
+    for event in track.events.iter() {
+        now += event.delta_time as u64;
+        tracker.update(&event.message);
+        let c = match event.message.channel() {
+            Some(c) => c,
+            None => { continue; }
+        };
+        let state = tracker.channel(c);
+        let semitones = state.pitch_bend();
+        let ms = ticks_to_ms(now, tempo);
+        let list = &mut changes[c];
+        let last_semitones = if list.is_empty() { 0.0 } else { list[list.len() - 1].semitones };
+        if semitones == last_semitones {
+            continue;
+        }
+        if !list.is_empty() && list[list.len() - 1].ms == ms {
+            list.pop();
+        }
+        list.push(BendChange { ms : ms, semitones : semitones });
+    }
+    changes
+}
+
+/// Cuts a script down to the stretch between `start_ms` and `end_ms`, both measured from the start
+/// of the track. Notes hanging over either edge get trimmed rather than dropped, and the result
+/// starts counting from `start_ms`.
+pub fn window(script : &BeepScript, start_ms : u32, end_ms : u32) -> BeepScript {
+    let mut spans = ~[];
+    let mut now = script.lead_in_ms;
+    for beep in script.beeps.iter() {
+        let beep_start = now;
+        let beep_end = now + beep.length_ms;
+        now = beep_end + beep.delay_ms;
+
+        let clipped_start = max(beep_start, start_ms);
+        let clipped_end = min(beep_end, end_ms);
+        if clipped_end > clipped_start {
+            spans.push(Span { key : beep.key,
+                              frequency : beep.frequency,
+                              start_ms : clipped_start - start_ms,
+                              end_ms : clipped_end - start_ms });
+        }
+    }
+    script_from_spans(spans)
+}
+
+/// Writes a BeepScript out as the text of a bash script. `title` ends up in the header comment.
+pub fn render_script(script : &BeepScript, title : &str) -> ~str {
+    let mut out = ~"#!/bin/bash\n#\n# ";
+    // The title can come from track names inside the MIDI file. A newline in there would end the
+    // comment and let the file run whatever it liked, so control characters become spaces.
+    for c in title.chars() {
+        out.push_char(if c < ' ' || c == '\x7f' { ' ' } else { c });
+    }
+    out.push_str("\n\n");
+    if script.lead_in_ms > 0 {
+        out.push_str(format!("sleep {:.3f}\n", (script.lead_in_ms as f64) / 1000.0));
+    }
+    for beep in script.beeps.iter() {
+        let line = format!("beep -f {:.2f} -l {} -D {}", beep.frequency, beep.length_ms, beep.delay_ms);
+        out.push_str(format!("{:<40}", line));
+        out.push_str("# ");
+        out.push_str(key_name(beep.key));
+        out.push_str("\n");
+    }
+    out
+}
+
+/// Scientific pitch notation for a MIDI key, so 60 is "C4" and 70 is "A#4".
+pub fn key_name(key : u8) -> ~str {
+    let names = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+    let octave = (key as int) / 12 - 1;
+    format!("{}{}", names[key % 12], octave)
+}
+
+/// Lays non-overlapping spans out end to end, turning the gaps between them into delays.
+fn script_from_spans(spans : &[Span]) -> BeepScript {
+    let mut beeps = ~[];
+    for i in range(0, spans.len()) {
+        let span = spans[i];
+        let delay_ms = if i + 1 < spans.len() {
+            spans[i + 1].start_ms - span.end_ms
+        } else {
+            0
+        };
+        beeps.push(Beep { key : span.key,
+                          frequency : span.frequency,
+                          length_ms : span.end_ms - span.start_ms,
+                          delay_ms : delay_ms });
+    }
+
+    let lead_in_ms = if spans.is_empty() { 0 } else { spans[0].start_ms };
+    BeepScript { lead_in_ms : lead_in_ms, beeps : beeps }
+}
+
+/// Converts absolute ticks to milliseconds, at whatever tempos were in effect along the way.
+fn ticks_to_ms(ticks : u64, tempo : &TempoMap) -> u32 {
+    (tempo.ticks_to_micros(ticks) / 1000) as u32
+}
+
+
+// Tests!
+
+/// Read from the root of the repository, which is where `make test` runs the tests.
+#[cfg(test)]
+static TEMPO_CHANGES : &'static str = "midis/tempo_changes.mid";
+
+#[cfg(test)]
+fn settings(voicing : Voicing) -> Settings {
+    Settings { voicing : voicing, tuning : Tuning::equal(440.0), bend_step_ms : 20, pedals : NO_PEDALS }
+}
+
+/// 120 BPM throughout, as if the file had no tempo events at all.
+#[cfg(test)]
+fn steady(division : Division) -> TempoMap {
+    TempoMap::for_track(division, &MidiTrack::from_messages(~[]))
+}
+
+#[test]
+fn test_key_name() {
+    assert!(key_name(60) == ~"C4");
+    assert!(key_name(70) == ~"A#4");
+    assert!(key_name(0) == ~"C-1");
+}
+
+#[test]
+fn test_compile_track_rests() {
+    // Two quarter notes at 96 ticks per quarter, with an eighth rest in between.
+    let track = MidiTrack::from_messages(~[(0,   NoteOn  { channel : 0, key : 62, velocity : 100 }),
+                                           (96,  NoteOff { channel : 0, key : 62, velocity : 0 }),
+                                           (48,  NoteOn  { channel : 0, key : 64, velocity : 100 }),
+                                           (96,  NoteOn  { channel : 0, key : 64, velocity : 0 })]);
+    let script = compile_track(&track, &steady(TicksPerQuarter(96)), &settings(Reduced(MostRecent)));
+    assert!(script.lead_in_ms == 0);
+    assert!(script.beeps.len() == 2);
+    assert!(script.beeps[0].length_ms == 500);
+    assert!(script.beeps[0].delay_ms == 250);
+    assert!(script.beeps[1].length_ms == 500);
+    assert!(script.beeps[1].delay_ms == 0);
+}
+
+#[test]
+fn test_compile_track_overlap() {
+    // The second note starts before the first is released, so it cuts the first one short.
+    let track = MidiTrack::from_messages(~[(96, NoteOn  { channel : 0, key : 60, velocity : 100 }),
+                                           (48, NoteOn  { channel : 0, key : 67, velocity : 100 }),
+                                           (48, NoteOff { channel : 0, key : 60, velocity : 0 }),
+                                           (48, NoteOff { channel : 0, key : 67, velocity : 0 })]);
+    let script = compile_track(&track, &steady(TicksPerQuarter(96)), &settings(Reduced(MostRecent)));
+    assert!(script.lead_in_ms == 500);
+    assert!(script.beeps.len() == 2);
+    assert!(script.beeps[0].key == 60);
+    assert!(script.beeps[0].length_ms == 250);
+    assert!(script.beeps[0].delay_ms == 0);
+    assert!(script.beeps[1].key == 67);
+    assert!(script.beeps[1].length_ms == 500);
+}
+
+#[test]
+fn test_compile_track_smpte() {
+    // 25 frames per second at 40 ticks per frame is a tick per millisecond, whatever the tempo.
+    let track = MidiTrack::from_messages(~[(100, NoteOn  { channel : 0, key : 69, velocity : 100 }),
+                                           (250, NoteOff { channel : 0, key : 69, velocity : 0 }),
+                                           (50,  NoteOn  { channel : 0, key : 71, velocity : 100 }),
+                                           (125, NoteOff { channel : 0, key : 71, velocity : 0 })]);
+    let script = compile_track(&track, &steady(Smpte{ frames_per_second : 25, ticks_per_frame : 40 }),
+                               &settings(Reduced(MostRecent)));
+    assert!(script.lead_in_ms == 100);
+    assert!(script.beeps[0].length_ms == 250);
+    assert!(script.beeps[0].delay_ms == 50);
+    assert!(script.beeps[1].length_ms == 125);
+}
+
+#[test]
+fn test_window() {
+    // Three half-second notes back to back, starting half a second in.
+    let track = MidiTrack::from_messages(~[(96, NoteOn { channel : 0, key : 60, velocity : 100 }),
+                                           (96, NoteOn { channel : 0, key : 62, velocity : 100 }),
+                                           (96, NoteOn { channel : 0, key : 64, velocity : 100 }),
+                                           (96, NoteOff { channel : 0, key : 64, velocity : 0 })]);
+    let full = compile_track(&track, &steady(TicksPerQuarter(96)), &settings(Reduced(MostRecent)));
+    let script = window(&full, 750, 1750);
+    assert!(script.lead_in_ms == 0);
+    assert!(script.beeps.len() == 3);
+    assert!(script.beeps[0].key == 60);
+    assert!(script.beeps[0].length_ms == 250);
+    assert!(script.beeps[1].key == 62);
+    assert!(script.beeps[1].length_ms == 500);
+    assert!(script.beeps[2].key == 64);
+    assert!(script.beeps[2].length_ms == 250);
+
+    // Nothing has started yet in the first 400ms.
+    let early = window(&full, 0, 400);
+    assert!(early.beeps.is_empty());
+}
+
+#[test]
+fn test_render_script() {
+    let track = MidiTrack::from_messages(~[(0,  NoteOn { channel : 0, key : 62, velocity : 100 }),
+                                           (48, NoteOn { channel : 0, key : 62, velocity : 0 })]);
+    let script = compile_track(&track, &steady(TicksPerQuarter(96)), &settings(Reduced(MostRecent)));
+    let text = render_script(&script, "test");
+    assert!(text.starts_with("#!/bin/bash\n#\n# test\n\n"));
+    assert!(text.contains("beep -f 293.66 -l 250 -D 0"));
+    assert!(text.contains("# D4"));
+
+    let low = Settings { voicing : Reduced(MostRecent), tuning : Tuning::equal(432.0), bend_step_ms : 20,
+                          pedals : NO_PEDALS };
+    let text = render_script(&compile_track(&track, &steady(TicksPerQuarter(96)), &low), "test");
+    assert!(text.contains("beep -f 288.33 -l 250 -D 0"));
+
+    let sneaky = render_script(&script, "name\nrm -rf ~");
+    assert!(sneaky.starts_with("#!/bin/bash\n#\n# name rm -rf ~\n\n"));
+}
+
+#[test]
+fn test_compile_track_chord() {
+    // A held chord under a melody note: with Highest, the melody wins and the chord comes back.
+    let track = MidiTrack::from_messages(~[(0,  NoteOn  { channel : 0, key : 60, velocity : 100 }),
+                                           (0,  NoteOn  { channel : 0, key : 64, velocity : 100 }),
+                                           (48, NoteOn  { channel : 0, key : 72, velocity : 100 }),
+                                           (48, NoteOff { channel : 0, key : 72, velocity : 0 }),
+                                           (96, NoteOff { channel : 0, key : 60, velocity : 0 }),
+                                           (0,  NoteOff { channel : 0, key : 64, velocity : 0 })]);
+    let script = compile_track(&track, &steady(TicksPerQuarter(96)), &settings(Reduced(Highest)));
+    assert!(script.beeps.iter().map(|b| (b.key, b.length_ms)).collect::<~[(u8, u32)]>()
+            == ~[(64, 250), (72, 250), (64, 500)]);
+}
+
+#[test]
+fn test_compile_track_arpeggio() {
+    // A C major triad for a quarter note (500ms), then E alone for an eighth.
+    let track = MidiTrack::from_messages(~[(0,  NoteOn  { channel : 0, key : 67, velocity : 100 }),
+                                           (0,  NoteOn  { channel : 0, key : 60, velocity : 100 }),
+                                           (0,  NoteOn  { channel : 0, key : 64, velocity : 100 }),
+                                           (96, NoteOff { channel : 0, key : 60, velocity : 0 }),
+                                           (0,  NoteOff { channel : 0, key : 67, velocity : 0 }),
+                                           (48, NoteOff { channel : 0, key : 64, velocity : 0 })]);
+    let script = compile_track(&track, &steady(TicksPerQuarter(96)), &settings(Arpeggiated(30)));
+    // 16 full turns of 30ms, then 20ms left over for the 17th.
+    assert!(script.beeps.len() == 18);
+    assert!(script.beeps.iter().take(4).map(|b| b.key).collect::<~[u8]>() == ~[60, 64, 67, 60]);
+    assert!(script.beeps[15].length_ms == 30);
+    assert!(script.beeps[16].key == 64);
+    assert!(script.beeps[16].length_ms == 20);
+    assert!(script.beeps[16].delay_ms == 0);
+    // The E on its own isn't chopped up at all.
+    assert!(script.beeps[17].key == 64);
+    assert!(script.beeps[17].length_ms == 250);
+    let total = script.beeps.iter().fold(0u32, |sum, b| sum + b.length_ms + b.delay_ms);
+    assert!(total == 750);
+}
+
+#[cfg(test)]
+fn frequencies(script : &BeepScript) -> ~[(~str, u32)] {
+    script.beeps.iter().map(|b| (format!("{:.2f}", b.frequency), b.length_ms)).collect()
+}
+
+#[test]
+fn test_compile_track_pitch_bend() {
+    // Half way up the wheel is a semitone at the default range of 2, then 6 once RPN 0,0 sets the
+    // range to 12.
+    let track = MidiTrack::from_messages(~[(0,  NoteOn        { channel : 0, key : 69, velocity : 100 }),
+                                           (48, PitchWheel    { channel : 0, lsb : 0, msb : 96 }),
+                                           (48, NoteOff       { channel : 0, key : 69, velocity : 0 }),
+                                           (0,  ControlChange { channel : 0, controller : 101, value : 0 }),
+                                           (0,  ControlChange { channel : 0, controller : 100, value : 0 }),
+                                           (0,  ControlChange { channel : 0, controller : 6, value : 12 }),
+                                           (0,  NoteOn        { channel : 0, key : 69, velocity : 100 }),
+                                           // Other channels bend on their own.
+                                           (0,  PitchWheel    { channel : 1, lsb : 0, msb : 0 }),
+                                           (48, NoteOff       { channel : 0, key : 69, velocity : 0 })]);
+    let script = compile_track(&track, &steady(TicksPerQuarter(96)), &settings(Reduced(MostRecent)));
+    assert!(frequencies(&script) == ~[(~"440.00", 250), (~"466.16", 250), (~"622.25", 250)]);
+
+    // Only the wheel bends notes; other RPNs, like coarse tuning (RPN 2), don't.
+    let tuned = MidiTrack::from_messages(~[(0,  ControlChange { channel : 0, controller : 101, value : 0 }),
+                                           (0,  ControlChange { channel : 0, controller : 100, value : 2 }),
+                                           (0,  ControlChange { channel : 0, controller : 6, value : 76 }),
+                                           (0,  NoteOn        { channel : 0, key : 69, velocity : 100 }),
+                                           (48, NoteOff       { channel : 0, key : 69, velocity : 0 })]);
+    let script = compile_track(&tuned, &steady(TicksPerQuarter(96)), &settings(Reduced(MostRecent)));
+    assert!(frequencies(&script) == ~[(~"440.00", 250)]);
+}
+
+#[test]
+fn test_compile_track_bend_steps() {
+    // A tick a millisecond, and the wheel wobbling every 5ms from 10ms to 30ms, faster than the
+    // 20ms steps can follow. The step from 20ms catches it up, and then it holds.
+    let up = PitchWheel { channel : 0, lsb : 0, msb : 96 };
+    let centre = PitchWheel { channel : 0, lsb : 0, msb : 64 };
+    let track = MidiTrack::from_messages(~[(0,  NoteOn  { channel : 0, key : 69, velocity : 100 }),
+                                           (10, up), (5, centre), (5, up), (5, centre), (5, up),
+                                           (70, NoteOff { channel : 0, key : 69, velocity : 0 })]);
+    let division = Smpte{ frames_per_second : 25, ticks_per_frame : 40 };
+    let script = compile_track(&track, &steady(division), &settings(Reduced(MostRecent)));
+    assert!(frequencies(&script) == ~[(~"440.00", 20), (~"466.16", 80)]);
+}
+
+#[test]
+fn test_compile_track_pedals() {
+    // A staccato note with the sustain pedal down, so it rings on until the pedal comes up.
+    let track = MidiTrack::from_messages(~[(0,  NoteOn  { channel : 0, key : 62, velocity : 100 }),
+                                           (0,  ControlChange { channel : 0, controller : 64, value : 127 }),
+                                           (24, NoteOff { channel : 0, key : 62, velocity : 0 }),
+                                           (72, ControlChange { channel : 0, controller : 64, value : 0 }),
+                                           (0,  NoteOn  { channel : 0, key : 64, velocity : 100 }),
+                                           (96, NoteOff { channel : 0, key : 64, velocity : 0 })]);
+    let plain = compile_track(&track, &steady(TicksPerQuarter(96)), &settings(Reduced(MostRecent)));
+    assert!(plain.beeps[0].length_ms == 125);
+    assert!(plain.beeps[0].delay_ms == 375);
+
+    let mut pedalled = settings(Reduced(MostRecent));
+    pedalled.pedals = ALL_PEDALS;
+    let script = compile_track(&track, &steady(TicksPerQuarter(96)), &pedalled);
+    assert!(script.beeps.len() == 2);
+    assert!(script.beeps[0].length_ms == 500);
+    assert!(script.beeps[0].delay_ms == 0);
+    assert!(script.beeps[1].length_ms == 500);
+}
+
+#[test]
+fn test_compile_track_tempo_changes() {
+    // A format 1 file with its tempos in the first track: 120 BPM, then 240 from beat 3, then
+    // slowing to 60 from beat 5. The tune is in the second track, with notes and rests that run
+    // across both changes.
+    let file = match parse_file(TEMPO_CHANGES) {
+        Ok(file) => file,
+        Err(e) => { fail!("Couldn't parse {}: {}", TEMPO_CHANGES, e.to_str()); }
+    };
+    let script = compile_track(&file.tracks[1], &TempoMap::new(&file), &settings(Reduced(MostRecent)));
+    assert!(script.lead_in_ms == 0);
+    assert!(script.beeps.iter().map(|b| (b.key, b.length_ms, b.delay_ms)).collect::<~[(u8, u32, u32)]>()
+            == ~[(60, 500, 250), (62, 375, 125), (64, 750, 0), (65, 1000, 0)]);
+
+    // Going by the second track alone, it all stays at 120 BPM.
+    let alone = TempoMap::for_track(file.header.division, &file.tracks[1]);
+    let script = compile_track(&file.tracks[1], &alone, &settings(Reduced(MostRecent)));
+    assert!(script.beeps.iter().map(|b| (b.key, b.length_ms, b.delay_ms)).collect::<~[(u8, u32, u32)]>()
+            == ~[(60, 500, 250), (62, 500, 250), (64, 750, 0), (65, 500, 0)]);
+}
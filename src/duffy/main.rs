@@ -1,14 +1,198 @@
-// Simple executable that reads a file, parses it, and pretty-prints it.
+// Reads a MIDI file and writes out a `beep` script for each of its tracks.
 extern mod midi;
-use midi::{parse_file, pretty_print};
+use std::os;
+use std::io::{File, io_error, stderr};
+use std::path::Path;
+use midi::{parse_file, text_to_str, MidiTrack, MultipleAsynchronous};
+use midi::melody::melody_track;
+use midi::notes::{ALL_PEDALS, NO_PEDALS};
+use midi::timing::TempoMap;
+use beep::{Settings, compile_track, render_script, window};
+use options::{Options, parse_args, USAGE};
+use tuning::{Tuning, Scale, KeyMapping, Equal, STANDARD_A4, parse_scl, parse_kbm};
+
+mod beep;
+mod options;
+mod reduce;
+mod tuning;
 
 fn main() {
-    match parse_file("la_overworld.mid") {
-        Some(file) => {
-            pretty_print(file);
+    let args = os::args();
+    let options = match parse_args(args) {
+        Ok(o) => o,
+        Err(msg) => {
+            usage_error(msg);
+            return;
+        }
+    };
+    if options.show_help {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let settings = match load_tuning(&options) {
+        Ok(tuning) => Settings { voicing : options.voicing,
+                                 tuning : tuning,
+                                 bend_step_ms : options.bend_step_ms,
+                                 pedals : if options.pedals { ALL_PEDALS } else { NO_PEDALS } },
+        Err(msg) => {
+            complain(msg);
+            os::set_exit_status(1);
+            return;
+        }
+    };
+
+    let input = options.input.as_slice();
+    match parse_file(input) {
+        Ok(file) => {
+            let num_tracks = file.tracks.len();
+            let selected = match options.tracks {
+                Some(ref tracks) => tracks.clone(),
+                None if options.find_melody => {
+                    match melody_track(&file) {
+                        Some(i) => {
+                            println!("Track {} looks like the melody.", i + 1);
+                            ~[i + 1]
+                        }
+                        None => {
+                            complain(format!("{} doesn't have any notes to pick a melody from.", input));
+                            os::set_exit_status(1);
+                            return;
+                        }
+                    }
+                }
+                None => range(1, num_tracks + 1).collect::<~[uint]>()
+            };
+            for &n in selected.iter() {
+                if n > num_tracks {
+                    usage_error(format!("There's no track {} -- {} only has {} tracks.", n, input, num_tracks));
+                    return;
+                }
+            }
+
+            let stem = match Path::new(input).filestem_str() {
+                Some(s) => s.to_owned(),
+                None => ~"duffy"
+            };
+            let channels = range(0u8, 16).filter(|&c| options.wants_channel(c)).collect::<~[u8]>();
+            let shared = TempoMap::new(&file);
+            for &track_number in selected.iter() {
+                let original = &file.tracks[track_number - 1];
+                // Format 2 tracks are separate pieces with their own tempos. Otherwise the tempo
+                // changes can be in any track (usually the first), and apply to all of them.
+                let tempo = if file.header.file_format == MultipleAsynchronous {
+                    TempoMap::for_track(file.header.division, original)
+                } else {
+                    shared.clone()
+                };
+                let track = original.filter_channels(channels);
+                if options.split_channels {
+                    let parts = track.split_channels();
+                    for &(channel, ref part) in parts.iter() {
+                        encode(&options, &settings, &tempo, stem, track_number, Some(channel), part);
+                    }
+                } else {
+                    encode(&options, &settings, &tempo, stem, track_number, None, &track);
+                }
+            }
         }
-        None => {
-            println!("Didn't work.");
+        Err(e) => {
+            complain(format!("Couldn't parse {} as a MIDI file: {}", input, e.to_str()));
+            os::set_exit_status(1);
         }
     }
 }
+
+/// Compiles and writes out the script for one track, or one channel of it when `channel` is given.
+fn encode(options : &Options, settings : &Settings, tempo : &TempoMap, stem : &str, track_number : uint,
+          channel : Option<u8>, track : &MidiTrack) {
+    let full = compile_track(track, tempo, settings);
+    let script = window(&full, options.start_ms, options.stop_ms());
+    let (label, filename) = match channel {
+        Some(c) => (format!("Track {} channel {}", track_number, c + 1),
+                    format!("{}_track{}_ch{}.sh", stem, track_number, c + 1)),
+        None => (format!("Track {}", track_number), format!("{}_track{}.sh", stem, track_number))
+    };
+    if script.beeps.is_empty() {
+        println!("{} has no notes in that stretch, skipping.", label);
+        return;
+    }
+    let title = describe(options, label, track.name());
+    if write_script(filename, render_script(&script, title)) {
+        println!("Wrote {}", filename);
+    } else {
+        complain(format!("Couldn't write {}", filename));
+        os::set_exit_status(1);
+    }
+}
+
+/// Puts together the tuning asked for by `--a4`, `--temperament`, `--scale` and `--keymap`.
+fn load_tuning(options : &Options) -> Result<Tuning, ~str> {
+    let scale = match options.scale_file {
+        Some(ref filename) => {
+            match read_text(*filename).and_then(|text| parse_scl(text)) {
+                Ok(scale) => scale,
+                Err(e) => { return Err(format!("Couldn't use {} as a scale: {}", *filename, e)); }
+            }
+        }
+        None => Scale::from_temperament(options.temperament.unwrap_or(Equal))
+    };
+    let mapping = match options.keymap_file {
+        Some(ref filename) => {
+            match read_text(*filename).and_then(|text| parse_kbm(text)) {
+                Ok(mapping) => mapping,
+                Err(e) => { return Err(format!("Couldn't use {} as a keymap: {}", *filename, e)); }
+            }
+        }
+        None => KeyMapping::standard(options.a4_hz.unwrap_or(STANDARD_A4))
+    };
+    Tuning::new(scale, mapping)
+}
+
+/// Reads a whole text file. Scala files are meant to be plain ASCII, but descriptions in the wild
+/// are often Latin-1, so bytes are taken as Latin-1 rather than risk failing on them.
+fn read_text(filename : &str) -> Result<~str, ~str> {
+    let mut failure = None;
+    let bytes = do io_error::cond.trap(|e| {
+        failure = Some(e.to_str());
+    }).inside {
+        File::open(&Path::new(filename)).read_to_end()
+    };
+    match failure {
+        Some(desc) => Err(desc),
+        None => Ok(text_to_str(bytes))
+    }
+}
+
+/// The header comment for a script, so whoever finds it later knows where it came from.
+fn describe(options : &Options, label : &str, name : Option<~str>) -> ~str {
+    let track = match name {
+        Some(n) => format!("{} (\"{}\")", label, n),
+        None => label.to_owned()
+    };
+    format!("{} of {}, {:.3f}s to {:.3f}s", track, options.input,
+            (options.start_ms as f64) / 1000.0, (options.stop_ms() as f64) / 1000.0)
+}
+
+/// Writes `contents` to `filename`, returning false if anything went wrong along the way.
+fn write_script(filename : &str, contents : &str) -> bool {
+    let mut ok = true;
+    do io_error::cond.trap(|_| {
+        ok = false;
+    }).inside {
+        let mut file = File::create(&Path::new(filename));
+        file.write(contents.as_bytes());
+    }
+    ok
+}
+
+fn complain(msg : &str) {
+    let mut err = stderr();
+    err.write_line(msg);
+}
+
+fn usage_error(msg : &str) {
+    complain(msg);
+    complain(USAGE);
+    os::set_exit_status(1);
+}
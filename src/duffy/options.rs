@@ -0,0 +1,616 @@
+/** Command-line options, as documented in the README:
+ *
+ *     duffy <options> input
+ *
+ * Times can be written like a YouTube video tag ("1m32s", "45s", "0m33s"). Everything here is
+ * measured in milliseconds once it's parsed.
+ */
+
+use std::u32;
+use midi::DRUM_CHANNEL;
+use beep::{Voicing, Reduced, Arpeggiated};
+use reduce::{Strategy, MostRecent, strategy_from_str};
+use tuning::{Temperament, temperament_from_str};
+#[cfg(test)]
+use reduce::Highest;
+#[cfg(test)]
+use tuning::JustIntonation;
+
+/// Fine enough for vibrato to come through as vibrato, without thousands of lines of script.
+static DEFAULT_BEND_STEP_MS : u32 = 20;
+
+/// Many MIDI files loop forever, so scripts are cut off after this long unless told otherwise.
+static DEFAULT_MAX_LENGTH_MS : u32 = 25000;
+
+pub static USAGE : &'static str = "usage: duffy <options> input
+
+options:
+  --start=<time>          Skip this far into the file before encoding. Defaults to 0m0s.
+  --end=<time>            Stop encoding here. Defaults to the end of the file.
+  --max-length=<seconds>  Longest a script is allowed to run. Defaults to 25.
+  --tracks=<tracks>       Comma-separated track numbers to encode, e.g. 1,11,6, or "auto" to
+                          guess which one has the melody.
+  --reduce=<how>          Which note to play when several sound at once: highest, lowest,
+                          recent or loudest. Defaults to recent.
+  --arpeggio=<ms>         Play chords as fast arpeggios instead, this many milliseconds a note.
+  --channels=<channels>   Comma-separated channels (1 to 16) to encode. Defaults to all of them
+                          but 10, the drums.
+  --skip-channels=<channels>
+                          Channels to leave out. Defaults to 10, unless --channels is given.
+  --split-channels        Write a separate script for each channel of each track.
+  --bend-step=<ms>        How finely to follow the pitch wheel while it moves. Defaults to 20.
+  --pedals                Let the sustain and sostenuto pedals hold notes on, as they would be
+                          heard.
+  --a4=<hz>               What A4 is tuned to, e.g. 432 or 415. Defaults to 440.
+  --temperament=<name>    equal, pythagorean, just, meantone or werckmeister. Defaults to equal.
+  --scale=<file.scl>      Use a Scala scale file instead of a temperament.
+  --keymap=<file.kbm>     Use a Scala keyboard mapping, which sets its own reference pitch.
+  --help                  Print this message.
+
+<time> looks like a YouTube video tag: 1m32s, 45s, 0m33s.";
+
+pub struct Options {
+    input : ~str,
+    start_ms : u32,
+    end_ms : Option<u32>,
+    max_length_ms : u32,
+    /// 1-based track numbers, in the order they were asked for. None means every track, unless
+    /// `find_melody` is set.
+    tracks : Option<~[uint]>,
+    /// `--tracks=auto`: encode only the track that looks most like the melody.
+    find_melody : bool,
+    /// What happens to chords: `--reduce` or `--arpeggio`, but not both.
+    voicing : Voicing,
+    /// 1-based channel numbers to encode, as asked for. None means every channel.
+    channels : Option<~[uint]>,
+    /// 1-based channel numbers to leave out, even if `channels` has them.
+    skip_channels : ~[uint],
+    split_channels : bool,
+    /// None means the tuning's default, which is 440 Hz unless a keymap says otherwise.
+    a4_hz : Option<f64>,
+    temperament : Option<Temperament>,
+    scale_file : Option<~str>,
+    keymap_file : Option<~str>,
+    bend_step_ms : u32,
+    /// `--pedals`: notes last as long as the sustain and sostenuto pedals hold them.
+    pedals : bool,
+    show_help : bool
+}
+
+impl Options {
+    /// Whether to encode `channel`, counting from 0 the way MidiMessage does.
+    pub fn wants_channel(&self, channel : u8) -> bool {
+        let number = (channel as uint) + 1;
+        let chosen = match self.channels {
+            Some(ref channels) => channels.contains(&number),
+            None => true
+        };
+        chosen && !self.skip_channels.contains(&number)
+    }
+
+    /// Where encoding stops, once `--end` and `--max-length` have both had their say.
+    pub fn stop_ms(&self) -> u32 {
+        let longest = if u32::max_value - self.start_ms < self.max_length_ms {
+            u32::max_value
+        } else {
+            self.start_ms + self.max_length_ms
+        };
+        match self.end_ms {
+            Some(end) if end < longest => end,
+            _ => longest
+        }
+    }
+}
+
+/// Parses the full argument list (including the program name in `args[0]`). On bad input, the
+/// error is a message suitable for showing the user above the usage text.
+pub fn parse_args(args : &[~str]) -> Result<Options, ~str> {
+    let mut options = Options { input : ~"",
+                                start_ms : 0,
+                                end_ms : None,
+                                max_length_ms : DEFAULT_MAX_LENGTH_MS,
+                                tracks : None,
+                                find_melody : false,
+                                voicing : Reduced(MostRecent),
+                                channels : None,
+                                // Drums are just noise as beeps, so they're left out unless asked for.
+                                skip_channels : ~[(DRUM_CHANNEL as uint) + 1],
+                                split_channels : false,
+                                a4_hz : None,
+                                temperament : None,
+                                scale_file : None,
+                                keymap_file : None,
+                                bend_step_ms : DEFAULT_BEND_STEP_MS,
+                                pedals : false,
+                                show_help : false };
+    let mut input = None;
+    let mut voicing_given = None;
+    let mut skip_given = false;
+
+    for arg in args.slice_from(1).iter() {
+        if !arg.starts_with("--") {
+            match input {
+                None => { input = Some(arg.to_owned()); }
+                Some(_) => { return Err(format!("Only one input file, please (got \"{}\" too).", *arg)); }
+            }
+            continue;
+        }
+
+        let (name, value) = match arg.find('=') {
+            Some(i) => (arg.slice(2, i), Some(arg.slice_from(i + 1))),
+            None => (arg.slice_from(2), None)
+        };
+        match name {
+            "help" => {
+                match no_value(name, value) {
+                    Ok(()) => { options.show_help = true; }
+                    Err(e) => { return Err(e); }
+                }
+            }
+            "start" => {
+                match time_option(name, value) {
+                    Ok(ms) => { options.start_ms = ms; }
+                    Err(e) => { return Err(e); }
+                }
+            }
+            "end" => {
+                match time_option(name, value) {
+                    Ok(ms) => { options.end_ms = Some(ms); }
+                    Err(e) => { return Err(e); }
+                }
+            }
+            "max-length" => {
+                match seconds_option(name, value) {
+                    Ok(ms) => { options.max_length_ms = ms; }
+                    Err(e) => { return Err(e); }
+                }
+            }
+            "tracks" if value == Some("auto") => {
+                options.find_melody = true;
+            }
+            "tracks" => {
+                match tracks_option(name, value) {
+                    Ok(tracks) => { options.tracks = Some(tracks); }
+                    Err(e) => { return Err(e); }
+                }
+            }
+            "reduce" => {
+                match reduce_option(name, value) {
+                    Ok(strategy) => { options.voicing = Reduced(strategy); }
+                    Err(e) => { return Err(e); }
+                }
+            }
+            "channels" => {
+                match channels_option(name, value) {
+                    Ok(channels) => { options.channels = Some(channels); }
+                    Err(e) => { return Err(e); }
+                }
+            }
+            "skip-channels" => {
+                match channels_option(name, value) {
+                    Ok(channels) => {
+                        options.skip_channels = channels;
+                        skip_given = true;
+                    }
+                    Err(e) => { return Err(e); }
+                }
+            }
+            "split-channels" => {
+                match no_value(name, value) {
+                    Ok(()) => { options.split_channels = true; }
+                    Err(e) => { return Err(e); }
+                }
+            }
+            "bend-step" => {
+                match milliseconds_option(name, value) {
+                    Ok(ms) => { options.bend_step_ms = ms; }
+                    Err(e) => { return Err(e); }
+                }
+            }
+            "pedals" => {
+                match no_value(name, value) {
+                    Ok(()) => { options.pedals = true; }
+                    Err(e) => { return Err(e); }
+                }
+            }
+            "a4" => {
+                match hz_option(name, value) {
+                    Ok(hz) => { options.a4_hz = Some(hz); }
+                    Err(e) => { return Err(e); }
+                }
+            }
+            "temperament" => {
+                match temperament_option(name, value) {
+                    Ok(t) => { options.temperament = Some(t); }
+                    Err(e) => { return Err(e); }
+                }
+            }
+            "scale" => {
+                match require_value(name, value) {
+                    Ok(file) => { options.scale_file = Some(file.to_owned()); }
+                    Err(e) => { return Err(e); }
+                }
+            }
+            "keymap" => {
+                match require_value(name, value) {
+                    Ok(file) => { options.keymap_file = Some(file.to_owned()); }
+                    Err(e) => { return Err(e); }
+                }
+            }
+            "arpeggio" => {
+                match milliseconds_option(name, value) {
+                    Ok(ms) => { options.voicing = Arpeggiated(ms); }
+                    Err(e) => { return Err(e); }
+                }
+            }
+            _ => {
+                return Err(format!("Unknown option \"{}\".", *arg));
+            }
+        }
+        if name == "reduce" || name == "arpeggio" {
+            match voicing_given {
+                Some(other) if other != name => {
+                    return Err(format!("--{} and --{} don't go together.", other, name));
+                }
+                _ => { voicing_given = Some(name); }
+            }
+        }
+    }
+
+    if options.show_help {
+        return Ok(options);
+    }
+    // Asking for channels by name is asking for them, drums or not.
+    if options.channels.is_some() && !skip_given {
+        options.skip_channels = ~[];
+    }
+    match input {
+        Some(i) => { options.input = i; }
+        None => { return Err(~"No input file given."); }
+    }
+    match options.end_ms {
+        Some(end) if end <= options.start_ms => {
+            return Err(~"--end has to come after --start.");
+        }
+        _ => {}
+    }
+    if options.temperament.is_some() && options.scale_file.is_some() {
+        return Err(~"--temperament and --scale don't go together.");
+    }
+    if options.a4_hz.is_some() && options.keymap_file.is_some() {
+        return Err(~"--a4 and --keymap don't go together; the keymap sets its own reference pitch.");
+    }
+    Ok(options)
+}
+
+/// Parses a YouTube-style time tag into milliseconds. Hours, minutes and seconds are each
+/// optional but have to come in that order, so "1m32s", "45s", "2h" and "0m33s" are all fine. A
+/// bare number is taken as seconds.
+pub fn parse_time(time : &str) -> Option<u32> {
+    if time.is_empty() {
+        return None;
+    }
+
+    let mut total = 0u64;
+    let mut number : Option<u64> = None;
+    // Units have to get strictly smaller as we go: 3 is hours, 2 is minutes, 1 is seconds.
+    let mut last_rank = 4;
+    for c in time.chars() {
+        match c.to_digit(10) {
+            Some(d) => {
+                let n = number.unwrap_or(0) * 10 + (d as u64);
+                if n > (u32::max_value as u64) {
+                    return None;
+                }
+                number = Some(n);
+            }
+            None => {
+                let (rank, unit_ms) = match c {
+                    'h' => (3, 3600000u64),
+                    'm' => (2, 60000u64),
+                    's' => (1, 1000u64),
+                    _ => { return None; }
+                };
+                if rank >= last_rank {
+                    return None;
+                }
+                match number {
+                    Some(n) => { total += n * unit_ms; }
+                    None => { return None; }
+                }
+                last_rank = rank;
+                number = None;
+            }
+        }
+    }
+
+    match number {
+        // Trailing digits with no unit only make sense on their own, as plain seconds.
+        Some(n) if last_rank == 4 => { total = n * 1000; }
+        Some(_) => { return None; }
+        None => {}
+    }
+    if total > (u32::max_value as u64) {
+        None
+    } else {
+        Some(total as u32)
+    }
+}
+
+/// Parses "1,11,6" into [1, 11, 6]. Track numbers start at 1, like they do in the README, and each
+/// can only be asked for once.
+fn tracks_option(name : &str, value : Option<&str>) -> Result<~[uint], ~str> {
+    let list = match require_value(name, value) {
+        Ok(v) => v,
+        Err(e) => { return Err(e); }
+    };
+    let mut tracks = ~[];
+    for item in list.split(',') {
+        match from_str::<uint>(item.trim()) {
+            Some(n) if tracks.contains(&n) => { return Err(format!("--{} has track {} twice.", name, n)); }
+            Some(n) if n > 0 => { tracks.push(n); }
+            _ => { return Err(format!("\"{}\" isn't a track number (they start at 1).", item)); }
+        }
+    }
+    Ok(tracks)
+}
+
+/// Parses "1,2,10" into [1, 2, 10]. Channels are numbered 1 to 16, the way they are on a synth.
+fn channels_option(name : &str, value : Option<&str>) -> Result<~[uint], ~str> {
+    let list = match require_value(name, value) {
+        Ok(v) => v,
+        Err(e) => { return Err(e); }
+    };
+    let mut channels = ~[];
+    for item in list.split(',') {
+        match from_str::<uint>(item.trim()) {
+            Some(n) if n >= 1 && n <= 16 => { channels.push(n); }
+            _ => { return Err(format!("--{} wants channels from 1 to 16, not \"{}\".", name, item)); }
+        }
+    }
+    Ok(channels)
+}
+
+fn hz_option(name : &str, value : Option<&str>) -> Result<f64, ~str> {
+    let v = match require_value(name, value) {
+        Ok(v) => v,
+        Err(e) => { return Err(e); }
+    };
+    match from_str::<f64>(v) {
+        Some(hz) if hz > 0.0 => Ok(hz),
+        _ => Err(format!("--{} wants a frequency in Hz, like 440 or 432, not \"{}\".", name, v))
+    }
+}
+
+fn temperament_option(name : &str, value : Option<&str>) -> Result<Temperament, ~str> {
+    let v = match require_value(name, value) {
+        Ok(v) => v,
+        Err(e) => { return Err(e); }
+    };
+    match temperament_from_str(v) {
+        Some(t) => Ok(t),
+        None => Err(format!("--{} wants equal, pythagorean, just, meantone or werckmeister, not \"{}\".",
+                            name, v))
+    }
+}
+
+fn reduce_option(name : &str, value : Option<&str>) -> Result<Strategy, ~str> {
+    let v = match require_value(name, value) {
+        Ok(v) => v,
+        Err(e) => { return Err(e); }
+    };
+    match strategy_from_str(v) {
+        Some(strategy) => Ok(strategy),
+        None => Err(format!("--{} wants highest, lowest, recent or loudest, not \"{}\".", name, v))
+    }
+}
+
+fn time_option(name : &str, value : Option<&str>) -> Result<u32, ~str> {
+    let v = match require_value(name, value) {
+        Ok(v) => v,
+        Err(e) => { return Err(e); }
+    };
+    match parse_time(v) {
+        Some(ms) => Ok(ms),
+        None => Err(format!("--{} wants a time like 1m32s or 45s, not \"{}\".", name, v))
+    }
+}
+
+/// Whole seconds, handed back in milliseconds like everything else.
+fn seconds_option(name : &str, value : Option<&str>) -> Result<u32, ~str> {
+    let v = match require_value(name, value) {
+        Ok(v) => v,
+        Err(e) => { return Err(e); }
+    };
+    match from_str::<u32>(v) {
+        Some(seconds) if seconds > 0 && seconds <= u32::max_value / 1000 => Ok(seconds * 1000),
+        _ => Err(format!("--{} wants a positive number of seconds, not \"{}\".", name, v))
+    }
+}
+
+fn milliseconds_option(name : &str, value : Option<&str>) -> Result<u32, ~str> {
+    let v = match require_value(name, value) {
+        Ok(v) => v,
+        Err(e) => { return Err(e); }
+    };
+    match from_str::<u32>(v) {
+        Some(ms) if ms > 0 => Ok(ms),
+        _ => Err(format!("--{} wants a positive number of milliseconds, not \"{}\".", name, v))
+    }
+}
+
+/// Flags are either there or not, so they don't take a value.
+fn no_value(name : &str, value : Option<&str>) -> Result<(), ~str> {
+    match value {
+        Some(v) => Err(format!("--{} doesn't take a value, not even \"{}\".", name, v)),
+        None => Ok(())
+    }
+}
+
+fn require_value<'a>(name : &str, value : Option<&'a str>) -> Result<&'a str, ~str> {
+    match value {
+        Some(v) if !v.is_empty() => Ok(v),
+        _ => Err(format!("--{} needs a value, like --{}=...", name, name))
+    }
+}
+
+// Tests!
+#[cfg(test)]
+fn args(list : &[&str]) -> ~[~str] {
+    let mut owned = ~[~"duffy"];
+    for s in list.iter() {
+        owned.push(s.to_owned());
+    }
+    owned
+}
+
+#[test]
+fn test_parse_time() {
+    assert!(parse_time("1m32s") == Some(92000));
+    assert!(parse_time("45s") == Some(45000));
+    assert!(parse_time("0m33s") == Some(33000));
+    assert!(parse_time("0m0s") == Some(0));
+    assert!(parse_time("1h") == Some(3600000));
+    assert!(parse_time("2m") == Some(120000));
+    assert!(parse_time("90") == Some(90000));
+}
+
+#[test]
+fn test_parse_time_fail() {
+    assert!(parse_time("").is_none());
+    assert!(parse_time("s").is_none());
+    assert!(parse_time("1m32").is_none());
+    assert!(parse_time("32s1m").is_none());
+    assert!(parse_time("1m1m").is_none());
+    assert!(parse_time("1x").is_none());
+    assert!(parse_time("-1s").is_none());
+    assert!(parse_time("99999999999s").is_none());
+}
+
+#[test]
+fn test_parse_args_defaults() {
+    match parse_args(args(["song.mid"])) {
+        Ok(o) => {
+            assert!(o.input == ~"song.mid");
+            assert!(o.start_ms == 0);
+            assert!(o.end_ms.is_none());
+            assert!(o.stop_ms() == 25000);
+            assert!(o.tracks.is_none());
+            assert!(!o.find_melody);
+            assert!(o.voicing == Reduced(MostRecent));
+            assert!(o.wants_channel(0));
+            assert!(!o.wants_channel(9));
+            assert!(!o.split_channels);
+            assert!(o.a4_hz.is_none());
+            assert!(o.temperament.is_none());
+            assert!(o.bend_step_ms == 20);
+            assert!(!o.pedals);
+        }
+        Err(_) => { assert!(false); }
+    }
+}
+
+#[test]
+fn test_parse_args_everything() {
+    match parse_args(args(["--start=1m32s", "--end=2m", "--max-length=10", "--tracks=1,11,6",
+                           "--reduce=highest", "song.mid"])) {
+        Ok(o) => {
+            assert!(o.start_ms == 92000);
+            assert!(o.end_ms == Some(120000));
+            assert!(o.stop_ms() == 102000);
+            assert!(o.tracks == Some(~[1, 11, 6]));
+            assert!(o.voicing == Reduced(Highest));
+        }
+        Err(_) => { assert!(false); }
+    }
+    match parse_args(args(["--tracks=auto", "song.mid"])) {
+        Ok(o) => {
+            assert!(o.find_melody);
+            assert!(o.tracks.is_none());
+        }
+        Err(_) => { assert!(false); }
+    }
+    match parse_args(args(["--arpeggio=30", "--bend-step=5", "--pedals", "song.mid"])) {
+        Ok(o) => {
+            assert!(o.voicing == Arpeggiated(30));
+            assert!(o.bend_step_ms == 5);
+            assert!(o.pedals);
+        }
+        Err(_) => { assert!(false); }
+    }
+}
+
+#[test]
+fn test_parse_args_tuning() {
+    match parse_args(args(["--a4=432", "--temperament=just", "song.mid"])) {
+        Ok(o) => {
+            assert!(o.a4_hz == Some(432.0));
+            assert!(o.temperament == Some(JustIntonation));
+        }
+        Err(_) => { assert!(false); }
+    }
+    match parse_args(args(["--scale=pelog.scl", "--keymap=pelog.kbm", "song.mid"])) {
+        Ok(o) => {
+            assert!(o.scale_file == Some(~"pelog.scl"));
+            assert!(o.keymap_file == Some(~"pelog.kbm"));
+        }
+        Err(_) => { assert!(false); }
+    }
+    assert!(parse_args(args(["--a4=0", "a.mid"])).is_err());
+    assert!(parse_args(args(["--a4=A", "a.mid"])).is_err());
+    assert!(parse_args(args(["--temperament=mean", "a.mid"])).is_err());
+    assert!(parse_args(args(["--temperament=just", "--scale=a.scl", "a.mid"])).is_err());
+    assert!(parse_args(args(["--a4=415", "--keymap=a.kbm", "a.mid"])).is_err());
+}
+
+#[test]
+fn test_parse_args_channels() {
+    // Naming channel 10 brings the drums back.
+    match parse_args(args(["--channels=1,10", "--split-channels", "song.mid"])) {
+        Ok(o) => {
+            assert!(o.wants_channel(0));
+            assert!(!o.wants_channel(1));
+            assert!(o.wants_channel(9));
+            assert!(o.split_channels);
+        }
+        Err(_) => { assert!(false); }
+    }
+    match parse_args(args(["--skip-channels=2", "song.mid"])) {
+        Ok(o) => {
+            assert!(!o.wants_channel(1));
+            assert!(o.wants_channel(9));
+        }
+        Err(_) => { assert!(false); }
+    }
+    match parse_args(args(["--channels=1,10", "--skip-channels=10", "song.mid"])) {
+        Ok(o) => { assert!(!o.wants_channel(9)); }
+        Err(_) => { assert!(false); }
+    }
+}
+
+#[test]
+fn test_parse_args_fail() {
+    assert!(parse_args(args([])).is_err());
+    assert!(parse_args(args(["a.mid", "b.mid"])).is_err());
+    assert!(parse_args(args(["--start", "a.mid"])).is_err());
+    assert!(parse_args(args(["--start=soon", "a.mid"])).is_err());
+    assert!(parse_args(args(["--start=1m", "--end=30s", "a.mid"])).is_err());
+    assert!(parse_args(args(["--max-length=0", "a.mid"])).is_err());
+    assert!(parse_args(args(["--tracks=1,,2", "a.mid"])).is_err());
+    assert!(parse_args(args(["--tracks=0", "a.mid"])).is_err());
+    assert!(parse_args(args(["--tracks=1,1", "a.mid"])).is_err());
+    assert!(parse_args(args(["--tracks=2,1,2", "a.mid"])).is_err());
+    // Flags don't take values.
+    assert!(parse_args(args(["--help"])).is_ok());
+    assert!(parse_args(args(["--help=please"])).is_err());
+    assert!(parse_args(args(["--help=", "a.mid"])).is_err());
+    assert!(parse_args(args(["--split-channels=0", "a.mid"])).is_err());
+    assert!(parse_args(args(["--pedals=no", "a.mid"])).is_err());
+    assert!(parse_args(args(["--loud", "a.mid"])).is_err());
+    assert!(parse_args(args(["--reduce=middle", "a.mid"])).is_err());
+    assert!(parse_args(args(["--arpeggio=0", "a.mid"])).is_err());
+    assert!(parse_args(args(["--bend-step=0", "a.mid"])).is_err());
+    assert!(parse_args(args(["--channels=0", "a.mid"])).is_err());
+    assert!(parse_args(args(["--skip-channels=17", "a.mid"])).is_err());
+    assert!(parse_args(args(["--reduce=lowest", "--arpeggio=30", "a.mid"])).is_err());
+}
@@ -0,0 +1,206 @@
+/** Squeezing chords down to one note at a time.
+ *
+ * The PC speaker has one voice, but most MIDI tracks have chords and overlapping notes. This picks
+ * one note to play at every moment, according to a Strategy, and lays the result out as a line of
+ * Segments with the rests in between.
+ */
+
+use midi::notes::Note;
+
+/// How to pick the one note to play when several are sounding.
+#[deriving(Clone, Eq)]
+pub enum Strategy {
+    /// The highest key, which is usually the melody.
+    Highest,
+    /// The lowest key, for bass lines.
+    Lowest,
+    /// Whichever started last.
+    MostRecent,
+    /// Whichever was struck hardest.
+    Loudest
+}
+
+/// A stretch of the monophonic line, in ticks: part (or all) of a note, or a rest when `note` is
+/// None.
+#[deriving(Clone, Eq)]
+pub struct Segment {
+    note : Option<Note>,
+    start : u64,
+    end : u64
+}
+
+/// The notes sounding together between `start` and `end`, in the order they started. A rest has no
+/// notes at all.
+#[deriving(Clone, Eq)]
+pub struct Chord {
+    notes : ~[Note],
+    start : u64,
+    end : u64
+}
+
+/// Parses the value of `--reduce`.
+pub fn strategy_from_str(name : &str) -> Option<Strategy> {
+    match name {
+        "highest" => Some(Highest),
+        "lowest" => Some(Lowest),
+        "recent" => Some(MostRecent),
+        "loudest" => Some(Loudest),
+        _ => None
+    }
+}
+
+/// Reduces `notes` (in the order they started, like `TrackNotes::all` gives them) to a line of
+/// segments that don't overlap, running from the first note's start to the last note's end. A
+/// note can be split into several segments if something else takes over for a while and then
+/// lets it through again. When the strategy calls it a tie, the note that started later wins.
+pub fn reduce(notes : &[Note], strategy : Strategy) -> ~[Segment] {
+    let ticks = boundaries(notes);
+    let mut segments : ~[Segment] = ~[];
+    // Which note the last segment came from, so a note carrying on isn't split up for nothing.
+    let mut last_pick : Option<uint> = None;
+    for w in range(1, ticks.len()) {
+        let (start, end) = (ticks[w - 1], ticks[w]);
+        let pick = pick_note(notes, sounding_at(notes, start), strategy);
+        if !segments.is_empty() && pick == last_pick {
+            let last = segments.len() - 1;
+            segments[last].end = end;
+        } else {
+            segments.push(Segment{ note : pick.map(|i| notes[i]), start : start, end : end });
+        }
+        last_pick = pick;
+    }
+    segments
+}
+
+/// Splits `notes` (in the order they started) into the stretches where the same set of them is
+/// sounding, from the first note's start to the last note's end.
+pub fn chords(notes : &[Note]) -> ~[Chord] {
+    let ticks = boundaries(notes);
+    let mut chords : ~[Chord] = ~[];
+    let mut last_sounding : ~[uint] = ~[];
+    for w in range(1, ticks.len()) {
+        let (start, end) = (ticks[w - 1], ticks[w]);
+        let sounding = sounding_at(notes, start);
+        if !chords.is_empty() && sounding == last_sounding {
+            let last = chords.len() - 1;
+            chords[last].end = end;
+        } else {
+            chords.push(Chord{ notes : sounding.iter().map(|&i| notes[i]).collect(),
+                               start : start,
+                               end : end });
+        }
+        last_sounding = sounding;
+    }
+    chords
+}
+
+/// Every tick where a note starts or ends, in order.
+fn boundaries(notes : &[Note]) -> ~[u64] {
+    let mut ticks = ~[];
+    for note in notes.iter() {
+        ticks.push(note.start);
+        ticks.push(note.start + note.duration);
+    }
+    ticks.sort();
+    ticks.dedup();
+    ticks
+}
+
+/// The indices of the notes sounding at `tick`, in the order they started.
+fn sounding_at(notes : &[Note], tick : u64) -> ~[uint] {
+    let mut sounding = ~[];
+    for (i, note) in notes.iter().enumerate() {
+        if note.start <= tick && tick < note.start + note.duration {
+            sounding.push(i);
+        }
+    }
+    sounding
+}
+
+/// The index of the note to play out of those `sounding`.
+fn pick_note(notes : &[Note], sounding : &[uint], strategy : Strategy) -> Option<uint> {
+    let mut best : Option<uint> = None;
+    for &i in sounding.iter() {
+        let note = &notes[i];
+        best = match best {
+            None => Some(i),
+            Some(b) => {
+                // Notes come in the order they started, so on a tie the later one is the newer.
+                let current = &notes[b];
+                let better = match strategy {
+                    Highest => note.key >= current.key,
+                    Lowest => note.key <= current.key,
+                    MostRecent => true,
+                    Loudest => note.velocity >= current.velocity
+                };
+                if better { Some(i) } else { Some(b) }
+            }
+        };
+    }
+    best
+}
+
+
+// Tests!
+
+#[cfg(test)]
+fn note(key : u8, velocity : u8, start : u64, duration : u64) -> Note {
+    Note{ channel : 0, key : key, velocity : velocity, start : start, duration : duration,
+          release_velocity : None }
+}
+
+#[cfg(test)]
+fn keys(segments : &[Segment]) -> ~[(Option<u8>, u64, u64)] {
+    segments.iter().map(|s| (s.note.map(|n| n.key), s.start, s.end)).collect()
+}
+
+#[test]
+fn test_reduce_chord() {
+    // A C major chord held for a beat, with a quiet high C coming in over it halfway, then a rest and
+    // a lone D.
+    let notes = [note(60, 100, 0, 96), note(64, 90, 0, 96), note(67, 80, 0, 96),
+                 note(72, 40, 48, 96),
+                 note(62, 100, 192, 96)];
+    assert!(keys(reduce(notes, Highest)) == ~[(Some(67), 0, 48), (Some(72), 48, 144),
+                                              (None, 144, 192), (Some(62), 192, 288)]);
+    assert!(keys(reduce(notes, Lowest)) == ~[(Some(60), 0, 96), (Some(72), 96, 144),
+                                             (None, 144, 192), (Some(62), 192, 288)]);
+    assert!(keys(reduce(notes, MostRecent)) == ~[(Some(67), 0, 48), (Some(72), 48, 144),
+                                                 (None, 144, 192), (Some(62), 192, 288)]);
+    assert!(keys(reduce(notes, Loudest)) == ~[(Some(60), 0, 96), (Some(72), 96, 144),
+                                              (None, 144, 192), (Some(62), 192, 288)]);
+}
+
+#[test]
+fn test_reduce_resumes_held_note() {
+    // A long low note with a short high one on top: the low one comes back afterwards, as a
+    // separate segment.
+    let notes = [note(48, 100, 0, 192), note(72, 100, 48, 48)];
+    assert!(keys(reduce(notes, Highest)) == ~[(Some(48), 0, 48), (Some(72), 48, 96), (Some(48), 96, 192)]);
+
+    // Back to back notes of the same key stay separate, so they're heard as two.
+    let repeated = [note(60, 100, 0, 48), note(60, 100, 48, 48)];
+    assert!(reduce(repeated, Highest).len() == 2);
+
+    let nothing : ~[Note] = ~[];
+    assert!(reduce(nothing, Highest).is_empty());
+    assert!(strategy_from_str("loudest") == Some(Loudest));
+    assert!(strategy_from_str("loud").is_none());
+}
+
+#[test]
+fn test_chords() {
+    // Two notes together, then one of them carries on alone, then a rest, then a lone note.
+    let notes = [note(60, 100, 0, 96), note(64, 100, 0, 48), note(67, 100, 144, 48)];
+    let found = chords(notes);
+    assert!(found.len() == 4);
+    assert!(found[0].notes.iter().map(|n| n.key).collect::<~[u8]>() == ~[60, 64]);
+    assert!((found[0].start, found[0].end) == (0, 48));
+    assert!(found[1].notes.iter().map(|n| n.key).collect::<~[u8]>() == ~[60]);
+    assert!((found[1].start, found[1].end) == (48, 96));
+    assert!(found[2].notes.is_empty());
+    assert!((found[3].start, found[3].end) == (144, 192));
+
+    let nothing : ~[Note] = ~[];
+    assert!(chords(nothing).is_empty());
+}
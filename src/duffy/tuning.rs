@@ -0,0 +1,423 @@
+/** Turning MIDI keys into frequencies.
+ *
+ * A Tuning is a Scale (the pitches of one octave, or whatever the scale repeats at) and a
+ * KeyMapping (which key plays which step of the scale, and which key is tuned to what). Both come
+ * from Scala files, as described at http://www.huygens-fokker.org/scala/scl_format.html and
+ * http://www.huygens-fokker.org/scala/help.htm#mappings -- the built-in temperaments are written
+ * the same way, below.
+ */
+
+/// Concert pitch: A4 at 440 Hz.
+pub static STANDARD_A4 : f64 = 440.0;
+
+/// Built-in tunings for the twelve notes from C.
+#[deriving(Clone, Eq)]
+pub enum Temperament {
+    Equal,
+    Pythagorean,
+    JustIntonation,
+    /// Quarter-comma meantone.
+    Meantone,
+    /// Werckmeister III.
+    Werckmeister
+}
+
+/// The steps of a scale, in cents above its first note. The last step is the interval the scale
+/// repeats at, usually an octave.
+#[deriving(Clone)]
+pub struct Scale {
+    description : ~str,
+    cents : ~[f64]
+}
+
+/// Which key plays which step of the scale, as in a Scala .kbm file.
+#[deriving(Clone)]
+pub struct KeyMapping {
+    /// How many keys before the pattern repeats. 0 means every key is the next step of the scale.
+    size : uint,
+    first_key : u8,
+    last_key : u8,
+    /// The key that plays the scale's first note.
+    middle_key : u8,
+    /// The key tuned to `reference_hz`.
+    reference_key : u8,
+    reference_hz : f64,
+    /// The step the pattern repeats at, after `size` keys. Unused when `size` is 0.
+    octave_degree : uint,
+    /// The step played by each of the `size` keys from `middle_key` on. None leaves a key silent.
+    degrees : ~[Option<uint>]
+}
+
+#[deriving(Clone)]
+pub struct Tuning {
+    priv scale : Scale,
+    priv mapping : KeyMapping
+}
+
+static EQUAL : &'static str = "! 12-tone equal temperament
+Equal temperament
+12
+100.0
+200.0
+300.0
+400.0
+500.0
+600.0
+700.0
+800.0
+900.0
+1000.0
+1100.0
+2/1
+";
+
+static PYTHAGOREAN : &'static str = "! Pure fifths, from Db up to F#
+Pythagorean
+12
+256/243
+9/8
+32/27
+81/64
+4/3
+729/512
+3/2
+128/81
+27/16
+16/9
+243/128
+2/1
+";
+
+static JUST_INTONATION : &'static str = "! 5-limit
+Just intonation
+12
+16/15
+9/8
+6/5
+5/4
+4/3
+45/32
+3/2
+8/5
+5/3
+9/5
+15/8
+2/1
+";
+
+static MEANTONE : &'static str = "! Fifths narrowed by a quarter of a syntonic comma, for pure major thirds
+Quarter-comma meantone
+12
+76.049
+193.157
+310.265
+386.314
+503.422
+579.471
+696.578
+772.627
+889.735
+1006.843
+1082.892
+2/1
+";
+
+static WERCKMEISTER : &'static str = "! Four fifths narrowed by a quarter of a Pythagorean comma
+Werckmeister III
+12
+90.225
+192.180
+294.135
+390.225
+498.045
+588.270
+696.090
+792.180
+888.270
+996.090
+1092.180
+2/1
+";
+
+/// Parses the value of `--temperament`.
+pub fn temperament_from_str(name : &str) -> Option<Temperament> {
+    match name {
+        "equal" => Some(Equal),
+        "pythagorean" => Some(Pythagorean),
+        "just" => Some(JustIntonation),
+        "meantone" => Some(Meantone),
+        "werckmeister" => Some(Werckmeister),
+        _ => None
+    }
+}
+
+impl Scale {
+    pub fn from_temperament(temperament : Temperament) -> Scale {
+        let text = match temperament {
+            Equal => EQUAL,
+            Pythagorean => PYTHAGOREAN,
+            JustIntonation => JUST_INTONATION,
+            Meantone => MEANTONE,
+            Werckmeister => WERCKMEISTER
+        };
+        parse_scl(text).unwrap()
+    }
+
+    /// How far step `degree` is above the first note, counting steps past the end of the scale
+    /// as going round again.
+    fn degree_cents(&self, degree : uint) -> f64 {
+        let n = self.cents.len();
+        let period = self.cents[n - 1];
+        let rounds = (degree / n) as f64;
+        let step = degree % n;
+        rounds * period + if step == 0 { 0.0 } else { self.cents[step - 1] }
+    }
+}
+
+impl KeyMapping {
+    /// Every key one step up from the last, with middle C starting the scale and A4 tuned to
+    /// `a4_hz`. For a twelve note scale, that's the usual keyboard.
+    pub fn standard(a4_hz : f64) -> KeyMapping {
+        KeyMapping{ size : 0, first_key : 0, last_key : 127, middle_key : 60, reference_key : 69,
+                    reference_hz : a4_hz, octave_degree : 0, degrees : ~[] }
+    }
+}
+
+impl Tuning {
+    /// Fails if the scale has no steps, or the reference key doesn't play anything.
+    pub fn new(scale : Scale, mapping : KeyMapping) -> Result<Tuning, ~str> {
+        if scale.cents.is_empty() {
+            return Err(~"The scale doesn't have any notes.");
+        }
+        let tuning = Tuning{ scale : scale, mapping : mapping };
+        match tuning.cents(tuning.mapping.reference_key) {
+            Some(_) => Ok(tuning),
+            None => Err(format!("Key {} is the reference, but the keymap leaves it silent.",
+                                tuning.mapping.reference_key))
+        }
+    }
+
+    /// Equal temperament with A4 (key 69) at `a4_hz`: 440, or 432, or 415 for baroque pitch.
+    pub fn equal(a4_hz : f64) -> Tuning {
+        Tuning{ scale : Scale::from_temperament(Equal), mapping : KeyMapping::standard(a4_hz) }
+    }
+
+    /// The frequency of `key` in Hz, or None if the keymap leaves it silent.
+    pub fn frequency(&self, key : u8) -> Option<f64> {
+        let reference = self.cents(self.mapping.reference_key).unwrap();
+        self.cents(key).map(|c| self.mapping.reference_hz * 2f64.powf(&((c - reference) / 1200.0)))
+    }
+
+    /// How far `key` is above (or below) the middle key, in cents.
+    fn cents(&self, key : u8) -> Option<f64> {
+        let mapping = &self.mapping;
+        if key < mapping.first_key || key > mapping.last_key {
+            return None;
+        }
+        let distance = (key as int) - (mapping.middle_key as int);
+        if mapping.size == 0 {
+            let n = self.scale.cents.len();
+            let (rounds, step) = floor_div(distance, n as int);
+            let period = self.scale.cents[n - 1];
+            return Some((rounds as f64) * period + self.scale.degree_cents(step as uint));
+        }
+
+        let (rounds, index) = floor_div(distance, mapping.size as int);
+        let degree = if (index as uint) < mapping.degrees.len() {
+            mapping.degrees[index as uint]
+        } else {
+            None
+        };
+        degree.map(|d| {
+            (rounds as f64) * self.scale.degree_cents(mapping.octave_degree) + self.scale.degree_cents(d)
+        })
+    }
+}
+
+/// Reads a Scala scale (.scl) file: a description, a number of notes, and then that many pitches,
+/// each either cents (with a decimal point) or a ratio like 3/2 or 2. Lines starting with ! are
+/// comments.
+pub fn parse_scl(text : &str) -> Result<Scale, ~str> {
+    let mut lines = text.lines().filter(|l| !l.starts_with("!"));
+    let description = match lines.next() {
+        Some(l) => l.trim().to_owned(),
+        None => { return Err(~"The scale file is empty."); }
+    };
+    let mut lines = lines.filter(|l| !l.trim().is_empty());
+    let count = match lines.next().and_then(|l| first_word(l)).and_then(|w| from_str::<uint>(w)) {
+        Some(n) if n > 0 => n,
+        _ => { return Err(~"The scale file doesn't say how many notes it has."); }
+    };
+
+    let mut cents = ~[];
+    for line in lines.take(count) {
+        let word = first_word(line).unwrap();
+        match pitch_cents(word) {
+            Some(c) => { cents.push(c); }
+            None => { return Err(format!("\"{}\" isn't a pitch.", word)); }
+        }
+    }
+    if cents.len() < count {
+        return Err(format!("The scale file promises {} notes but has {}.", count, cents.len()));
+    }
+    Ok(Scale{ description : description, cents : cents })
+}
+
+/// Reads a Scala keyboard mapping (.kbm) file: the map size, first and last keys, middle key,
+/// reference key, reference frequency and octave degree, one to a line, followed by the map
+/// itself, where an x leaves a key silent. Lines starting with ! are comments.
+pub fn parse_kbm(text : &str) -> Result<KeyMapping, ~str> {
+    let words = text.lines()
+                    .filter(|l| !l.starts_with("!") && !l.trim().is_empty())
+                    .map(|l| first_word(l).unwrap())
+                    .collect::<~[&str]>();
+    if words.len() < 7 {
+        return Err(~"The keymap file is missing some of its settings.");
+    }
+
+    let mut numbers = ~[];
+    for (i, &word) in words.slice_to(7).iter().enumerate() {
+        // Everything but the reference frequency is a whole number, and the keys are MIDI keys.
+        let ok = match from_str::<f64>(word) {
+            Some(n) if i == 5 => n > 0.0,
+            Some(n) if i >= 1 && i <= 4 => n >= 0.0 && n <= 127.0 && n == n.floor(),
+            Some(n) => n >= 0.0 && n == n.floor(),
+            None => false
+        };
+        if !ok {
+            return Err(format!("\"{}\" doesn't make sense in a keymap there.", word));
+        }
+        numbers.push(from_str::<f64>(word).unwrap());
+    }
+
+    let size = numbers[0] as uint;
+    let mut degrees = ~[];
+    for &word in words.slice_from(7).iter().take(size) {
+        if word == "x" {
+            degrees.push(None);
+        } else {
+            match from_str::<uint>(word) {
+                Some(d) => { degrees.push(Some(d)); }
+                None => { return Err(format!("\"{}\" isn't a scale degree.", word)); }
+            }
+        }
+    }
+    Ok(KeyMapping{ size : size,
+                   first_key : numbers[1] as u8,
+                   last_key : numbers[2] as u8,
+                   middle_key : numbers[3] as u8,
+                   reference_key : numbers[4] as u8,
+                   reference_hz : numbers[5],
+                   octave_degree : numbers[6] as uint,
+                   degrees : degrees })
+}
+
+fn first_word<'a>(line : &'a str) -> Option<&'a str> {
+    line.words().next()
+}
+
+/// "701.955" is cents, "3/2" and "2" are ratios.
+fn pitch_cents(word : &str) -> Option<f64> {
+    if word.contains(".") {
+        return from_str::<f64>(word);
+    }
+    let (numerator, denominator) = match word.find('/') {
+        Some(i) => (from_str::<u64>(word.slice_to(i)), from_str::<u64>(word.slice_from(i + 1))),
+        None => (from_str::<u64>(word), Some(1))
+    };
+    match (numerator, denominator) {
+        (Some(n), Some(d)) if n > 0 && d > 0 => Some(1200.0 * ((n as f64) / (d as f64)).log2()),
+        _ => None
+    }
+}
+
+/// Division rounding down, with the remainder, so -1 / 12 is -1 remainder 11.
+fn floor_div(a : int, b : int) -> (int, int) {
+    let mut quotient = a / b;
+    let mut remainder = a % b;
+    if remainder < 0 {
+        quotient -= 1;
+        remainder += b;
+    }
+    (quotient, remainder)
+}
+
+
+// Tests!
+
+#[cfg(test)]
+fn hz(tuning : &Tuning, key : u8) -> ~str {
+    match tuning.frequency(key) {
+        Some(f) => format!("{:.2f}", f),
+        None => ~"silent"
+    }
+}
+
+#[test]
+fn test_equal_temperament() {
+    let standard = Tuning::equal(440.0);
+    assert!(hz(&standard, 69) == ~"440.00");
+    assert!(hz(&standard, 62) == ~"293.66");
+    assert!(hz(&standard, 81) == ~"880.00");
+    assert!(hz(&standard, 0) == ~"8.18");
+
+    assert!(hz(&Tuning::equal(432.0), 69) == ~"432.00");
+    assert!(hz(&Tuning::equal(432.0), 57) == ~"216.00");
+    assert!(hz(&Tuning::equal(415.0), 62) == ~"276.98");
+}
+
+#[test]
+fn test_temperaments() {
+    let mapping = KeyMapping::standard(440.0);
+    let tuned = |t| Tuning::new(Scale::from_temperament(t), mapping.clone()).unwrap();
+    // A stays put, and C and E fall where their ratios from A put them.
+    assert!(hz(&tuned(Pythagorean), 69) == ~"440.00");
+    assert!(hz(&tuned(Pythagorean), 60) == ~"260.74");
+    assert!(hz(&tuned(JustIntonation), 60) == ~"264.00");
+    assert!(hz(&tuned(JustIntonation), 64) == ~"330.00");
+    assert!(hz(&tuned(JustIntonation), 72) == ~"528.00");
+    assert!(hz(&tuned(Meantone), 64) == ~"328.98");
+    assert!(hz(&tuned(Werckmeister), 60) == ~"263.40");
+    assert!(temperament_from_str("just") == Some(JustIntonation));
+    assert!(temperament_from_str("mean").is_none());
+}
+
+#[test]
+fn test_parse_scl() {
+    let scale = parse_scl("! odd.scl\n!\nThree notes\n 3\n!\n100.0 cents\n5/4\n2\n").unwrap();
+    assert!(scale.description == ~"Three notes");
+    assert!(scale.cents.len() == 3);
+    assert!(format!("{:.2f}", scale.cents[1]) == ~"386.31");
+    assert!(scale.cents[2] == 1200.0);
+
+    // Every key is the next step, so the scale repeats every three keys from middle C, and A4 is
+    // exactly three rounds up.
+    let tuning = Tuning::new(scale, KeyMapping::standard(440.0)).unwrap();
+    assert!(hz(&tuning, 69) == ~"440.00");
+    assert!(hz(&tuning, 70) == ~"466.16");
+    assert!(hz(&tuning, 68) == ~"275.00");
+
+    assert!(parse_scl("").is_err());
+    assert!(parse_scl("Too short\n3\n100.0\n2/1\n").is_err());
+    assert!(parse_scl("Bad\n1\n3/0\n").is_err());
+}
+
+#[test]
+fn test_parse_kbm() {
+    // Twelve keys to the octave, but C# is left out and the range stops at C5.
+    let text = "! Comment\n12\n0\n72\n60\n69\n440.0\n12\n! Mapping\n0\nx\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n";
+    let mapping = parse_kbm(text).unwrap();
+    assert!(mapping.degrees.len() == 12);
+    let tuning = Tuning::new(Scale::from_temperament(Equal), mapping).unwrap();
+    assert!(hz(&tuning, 69) == ~"440.00");
+    assert!(hz(&tuning, 61) == ~"silent");
+    assert!(hz(&tuning, 49) == ~"silent");
+    assert!(hz(&tuning, 48) == ~"130.81");
+    assert!(hz(&tuning, 73) == ~"silent");
+
+    // The reference key can't be one that's left out.
+    let silent_a = parse_kbm("12\n0\n127\n60\n61\n440.0\n12\n0\nx\n").unwrap();
+    assert!(Tuning::new(Scale::from_temperament(Equal), silent_a).is_err());
+    assert!(parse_kbm("12\n0\n127\n60\n69\n").is_err());
+    assert!(parse_kbm("12\n0\n200\n60\n69\n440.0\n12\n").is_err());
+}
@@ -0,0 +1,321 @@
+/** What each channel is set to, rather than the messages that set it.
+ *
+ * A ControlChange on its own often means little. Controllers 0 to 31 are the coarse halves of
+ * 14-bit values whose fine halves are controllers 32 to 63, and some of the most important
+ * settings, like the pitch bend range, aren't controllers at all but registered parameters (RPNs),
+ * picked with CC 101 and 100 and then set through data entry on CC 6 and 38. Non-registered
+ * parameters (NRPNs) work the same way, picked with CC 99 and 98. A ChannelTracker follows all of
+ * that for all sixteen channels as the messages go by, and `channels_at` replays a track up to a
+ * given tick.
+ */
+
+use std::vec::from_elem;
+use super::{MidiTrack, MidiMessage, ControlChange, ProgramChange, PitchWheel};
+#[cfg(test)]
+use super::NoteOn;
+
+/// The registered parameters this knows the meaning of.
+pub static RPN_BEND_RANGE : u16 = 0;
+pub static RPN_FINE_TUNING : u16 = 1;
+pub static RPN_COARSE_TUNING : u16 = 2;
+
+/// CC 101 and 100 both at 127, or CC 99 and 98 both at 127, pick no parameter at all, so stray data
+/// entry goes nowhere.
+static NULL_PARAMETER : u16 = 0x3FFF;
+
+/// Which parameter data entry (CC 6 and 38) goes to, by 14-bit number.
+#[deriving(Clone, Eq)]
+pub enum Parameter {
+    Registered(u16),
+    NonRegistered(u16)
+}
+
+/// Everything set on one channel so far.
+#[deriving(Clone)]
+pub struct ChannelState {
+    /// The last value of each of the 128 controllers.
+    priv controllers : ~[u8],
+    priv selected : Option<Parameter>,
+    /// (parameter, 14-bit value) for every parameter that's been set, in the order they were first
+    /// set.
+    priv rpns : ~[(u16, u16)],
+    priv nrpns : ~[(u16, u16)],
+    /// 14 bits, with 8192 in the middle.
+    pitch_wheel : u16,
+    program : u8
+}
+
+/// The state of all sixteen channels, kept up to date by `update`.
+#[deriving(Clone)]
+pub struct ChannelTracker {
+    priv channels : ~[ChannelState]
+}
+
+impl ChannelState {
+    /// A channel as General MIDI leaves it after a reset: volume 100, pan in the middle,
+    /// expression all the way up, and the wheel centred.
+    pub fn new() -> ChannelState {
+        let mut controllers = from_elem(128, 0u8);
+        controllers[7] = 100;
+        controllers[10] = 64;
+        controllers[11] = 127;
+        ChannelState{ controllers : controllers, selected : None, rpns : ~[], nrpns : ~[],
+                      pitch_wheel : 8192, program : 0 }
+    }
+
+    /// Applies a message, as long as it's a channel message. Which channel it's for doesn't matter
+    /// here; see ChannelTracker.
+    pub fn update(&mut self, message : &MidiMessage) {
+        match *message {
+            ControlChange { controller : c, value : v, _ } => { self.control_change(c & 0x7F, v); }
+            ProgramChange { new_program : p, _ } => { self.program = p; }
+            PitchWheel { lsb : l, msb : m, _ } => { self.pitch_wheel = ((m as u16) << 7) | (l as u16); }
+            _ => {}
+        }
+    }
+
+    /// The last value of a controller, 0 to 127.
+    pub fn controller(&self, controller : u8) -> u8 {
+        self.controllers[controller & 0x7F]
+    }
+
+    /// Controllers 0 to 31 together with their fine halves, 32 to 63, as one 14-bit value. Anything
+    /// else is just the 7-bit value.
+    pub fn controller_14bit(&self, controller : u8) -> u16 {
+        if controller < 32 {
+            ((self.controller(controller) as u16) << 7) | (self.controller(controller + 32) as u16)
+        } else {
+            self.controller(controller) as u16
+        }
+    }
+
+    /// The parameter data entry is going to, if any.
+    pub fn selected(&self) -> Option<Parameter> {
+        self.selected
+    }
+
+    /// The 14-bit value of a registered parameter, if it's been set.
+    pub fn rpn(&self, parameter : u16) -> Option<u16> {
+        lookup(self.rpns, parameter)
+    }
+
+    /// The 14-bit value of a non-registered parameter, if it's been set.
+    pub fn nrpn(&self, parameter : u16) -> Option<u16> {
+        lookup(self.nrpns, parameter)
+    }
+
+    /// How far the wheel bends at full tilt, in semitones: the coarse half of RPN 0 is
+    /// semitones and the fine half cents. Two semitones until it's set.
+    pub fn bend_range(&self) -> f64 {
+        match self.rpn(RPN_BEND_RANGE) {
+            Some(v) => ((v >> 7) as f64) + ((v & 0x7F) as f64) / 100.0,
+            None => 2.0
+        }
+    }
+
+    /// How far the wheel is bending right now, in semitones.
+    pub fn pitch_bend(&self) -> f64 {
+        ((self.pitch_wheel as f64) - 8192.0) / 8192.0 * self.bend_range()
+    }
+
+    /// RPN 1, in cents: 14 bits covering -100 to just under +100.
+    pub fn fine_tuning(&self) -> f64 {
+        match self.rpn(RPN_FINE_TUNING) {
+            Some(v) => ((v as f64) - 8192.0) / 8192.0 * 100.0,
+            None => 0.0
+        }
+    }
+
+    /// RPN 2, in semitones. Only the coarse half counts, with 64 as no change.
+    pub fn coarse_tuning(&self) -> int {
+        match self.rpn(RPN_COARSE_TUNING) {
+            Some(v) => ((v >> 7) as int) - 64,
+            None => 0
+        }
+    }
+
+    /// CC 7 and 39, 14 bits.
+    pub fn volume(&self) -> u16 {
+        self.controller_14bit(7)
+    }
+
+    /// CC 10 and 42, 14 bits, with 8192 in the middle.
+    pub fn pan(&self) -> u16 {
+        self.controller_14bit(10)
+    }
+
+    /// Whether the sustain pedal (CC 64) is down.
+    pub fn sustain(&self) -> bool {
+        self.controller(64) >= 64
+    }
+
+    fn control_change(&mut self, controller : u8, value : u8) {
+        self.controllers[controller] = value;
+        match controller {
+            // Data entry, coarse and fine.
+            6 => { self.data_entry(value, true); }
+            38 => { self.data_entry(value, false); }
+            // A new coarse value starts a new 14-bit one, so the old fine half no longer applies.
+            0..31 => { self.controllers[controller + 32] = 0; }
+            99 | 98 => {
+                let number = ((self.controllers[99] as u16) << 7) | (self.controllers[98] as u16);
+                self.selected = if number == NULL_PARAMETER { None } else { Some(NonRegistered(number)) };
+            }
+            101 | 100 => {
+                let number = ((self.controllers[101] as u16) << 7) | (self.controllers[100] as u16);
+                self.selected = if number == NULL_PARAMETER { None } else { Some(Registered(number)) };
+            }
+            _ => {}
+        }
+    }
+
+    /// Sets the coarse or fine half of the selected parameter, leaving the other half be.
+    fn data_entry(&mut self, value : u8, coarse : bool) {
+        let selected = self.selected;
+        let (table, parameter) = match selected {
+            Some(Registered(p)) => (&mut self.rpns, p),
+            Some(NonRegistered(p)) => (&mut self.nrpns, p),
+            None => { return; }
+        };
+        let merge = |old : u16| {
+            if coarse { ((value as u16) << 7) | (old & 0x7F) } else { (old & !0x7F) | (value as u16) }
+        };
+        for entry in table.mut_iter() {
+            let (p, old) = *entry;
+            if p == parameter {
+                *entry = (p, merge(old));
+                return;
+            }
+        }
+        table.push((parameter, merge(0)));
+    }
+}
+
+impl ChannelTracker {
+    pub fn new() -> ChannelTracker {
+        ChannelTracker{ channels : from_elem(16, ChannelState::new()) }
+    }
+
+    /// Applies a message to whichever channel it's for. Anything but a channel message is ignored.
+    pub fn update(&mut self, message : &MidiMessage) {
+        match message.channel() {
+            Some(c) => { self.channels[c & 0x0F].update(message); }
+            None => {}
+        }
+    }
+
+    /// The state of a channel, counting from 0.
+    pub fn channel<'a>(&'a self, channel : u8) -> &'a ChannelState {
+        &self.channels[channel & 0x0F]
+    }
+}
+
+/// Every channel's state once all of a track's events up to and including `tick` (counting from
+/// the start of the track) have happened.
+pub fn channels_at(track : &MidiTrack, tick : u64) -> ChannelTracker {
+    let mut tracker = ChannelTracker::new();
+    let mut now = 0u64;
+    for event in track.events.iter() {
+        now += event.delta_time as u64;
+        if now > tick {
+            break;
+        }
+        tracker.update(&event.message);
+    }
+    tracker
+}
+
+fn lookup(table : &[(u16, u16)], parameter : u16) -> Option<u16> {
+    for &(p, value) in table.iter() {
+        if p == parameter {
+            return Some(value);
+        }
+    }
+    None
+}
+
+
+// Tests!
+
+#[cfg(test)]
+fn cc(channel : u8, controller : u8, value : u8) -> MidiMessage {
+    ControlChange{ channel : channel, controller : controller, value : value }
+}
+
+#[test]
+fn test_channel_defaults() {
+    let tracker = ChannelTracker::new();
+    let state = tracker.channel(3);
+    assert!(state.bend_range() == 2.0);
+    assert!(state.pitch_bend() == 0.0);
+    assert!(state.volume() == 100 << 7);
+    assert!(state.pan() == 8192);
+    assert!(!state.sustain());
+    assert!(state.coarse_tuning() == 0);
+    assert!(state.selected().is_none());
+}
+
+#[test]
+fn test_rpn_and_nrpn() {
+    let track = MidiTrack::from_messages(~[// Bend range to 12 semitones and 50 cents.
+                                           (0,  cc(0, 101, 0)), (0, cc(0, 100, 0)),
+                                           (0,  cc(0, 6, 12)), (0, cc(0, 38, 50)),
+                                           // Coarse tuning down a tone, and fine tuning up 50 cents.
+                                           (0,  cc(0, 100, 2)), (0, cc(0, 6, 62)),
+                                           (0,  cc(0, 100, 1)), (0, cc(0, 6, 96)), (0, cc(0, 38, 0)),
+                                           // An NRPN, which mustn't touch the RPNs.
+                                           (10, cc(0, 99, 1)), (0, cc(0, 98, 8)), (0, cc(0, 6, 20)),
+                                           // The null RPN, after which data entry goes nowhere.
+                                           (10, cc(0, 101, 127)), (0, cc(0, 100, 127)), (0, cc(0, 6, 1)),
+                                           (0,  PitchWheel{ channel : 0, lsb : 0, msb : 96 }),
+                                           (0,  NoteOn{ channel : 0, key : 60, velocity : 100 })]);
+    let before = channels_at(&track, 0);
+    let state = before.channel(0);
+    assert!(state.bend_range() == 12.5);
+    assert!(state.coarse_tuning() == -2);
+    assert!(state.fine_tuning() == 50.0);
+    assert!(state.nrpn(0x88).is_none());
+    assert!(state.pitch_bend() == 0.0);
+
+    let after = channels_at(&track, 20);
+    let state = after.channel(0);
+    assert!(state.selected().is_none());
+    assert!(state.nrpn(0x88) == Some(20 << 7));
+    assert!(state.rpn(RPN_BEND_RANGE) == Some((12 << 7) | 50));
+    assert!(state.pitch_bend() == 6.25);
+    // The other channels haven't heard a thing.
+    assert!(after.channel(1).bend_range() == 2.0);
+}
+
+#[test]
+fn test_null_nrpn() {
+    // The null NRPN deselects, just like the null RPN, so data entry after it goes nowhere.
+    let mut tracker = ChannelTracker::new();
+    for &(controller, value) in [(99, 1), (98, 8), (6, 20), (99, 127), (98, 127), (6, 5)].iter() {
+        tracker.update(&cc(0, controller, value));
+    }
+    let state = tracker.channel(0);
+    assert!(state.selected().is_none());
+    assert!(state.nrpn(0x88) == Some(20 << 7));
+    assert!(state.nrpn(NULL_PARAMETER).is_none());
+}
+
+#[test]
+fn test_14bit_controllers() {
+    let mut tracker = ChannelTracker::new();
+    tracker.update(&cc(2, 7, 90));
+    tracker.update(&cc(2, 39, 5));
+    tracker.update(&cc(2, 10, 0));
+    tracker.update(&cc(2, 64, 127));
+    tracker.update(&ProgramChange{ channel : 2, new_program : 80 });
+    let state = tracker.channel(2);
+    assert!(state.volume() == ((90 << 7) | 5));
+    assert!(state.pan() == 0);
+    assert!(state.sustain());
+    assert!(state.program == 80);
+    assert!(state.controller_14bit(64) == 127);
+
+    // A new coarse volume clears the old fine one.
+    tracker.update(&cc(2, 7, 91));
+    assert!(tracker.channel(2).volume() == 91 << 7);
+}
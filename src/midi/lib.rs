@@ -1,4 +1,4 @@
-#[feature(struct_variant)];
+#[feature(struct_variant, macro_rules)];
 #[link(name = "midi",
        vers = "0.1",
        package_id = "bd61bdf8938c3e8a50c7105b949065a1")];
@@ -27,56 +27,159 @@
 #[warn(non_camel_case_types)]
 
 use std::io::{File, io_error};
+use std::u16;
 use std::option::{Some, None};
 use std::path::Path;
 use std::vec::{with_capacity, append_one};
 
-// TODO:  Write a Rust macro to chain Option<> Pattern matches, so Nones always just return None,
-// but assume you got the Some(x)?
+pub mod channels;
+pub mod melody;
+pub mod notes;
+pub mod timing;
+
 // TODO: Parallelize the track reads rather than make it sequential?
 
 
 // Reading
-/// A MidiFile contains a Header, and a list of Tracks,
+/// A MidiFile contains a Header, and a list of Tracks, plus any chunks that are neither (kept so
+/// they can be written back out). `trailing_bytes` is whatever was left over after the last chunk
+/// that couldn't be read as one; only the lossless writer puts it back.
+#[deriving(Clone)]
 pub struct MidiFile {
     header: MidiHeader,
-    tracks: ~[MidiTrack]
+    tracks: ~[MidiTrack],
+    unknown_chunks: ~[UnknownChunk],
+    trailing_bytes: ~[u8]
 }
 
-/// A MidiHeader contains the FileFormat, the number of tracks, and the 'ticks' per quarter note.
+/// A MidiHeader contains the FileFormat, the number of tracks, and how long a 'tick' is. Headers
+/// longer than the standard six bytes keep whatever follows in `extra`.
+#[deriving(Clone)]
 pub struct MidiHeader {
     file_format: FileFormat,
     num_tracks: u16,
-    ticks_per_quarter: u16
+    division: Division,
+    extra: ~[u8]
+}
+
+/// A chunk that isn't `MThd` or `MTrk`. The spec says to skip these, and some tools insert their
+/// own. `after_track` is how many tracks came before it in the file, so it can be put back in the
+/// same place.
+#[deriving(Clone, Eq)]
+pub struct UnknownChunk {
+    id: ~[u8],
+    data: ~[u8],
+    after_track: uint
+}
+
+/// A chunk as it sits in the buffer: a four-byte ID, then as many bytes as the length says.
+/// `offset` is where the chunk (not its data) starts.
+pub struct RawChunk<'a> {
+    id: &'a [u8],
+    offset: u32,
+    data: &'a [u8]
+}
+
+/// Walks through the chunks in a buffer, one after another. See `chunks`.
+pub struct Chunks<'a> {
+    priv buf: &'a [u8],
+    priv offset: u32,
+    priv failed: bool
 }
 
 /// A Miditrack itself only contains its own length and a list of the events.
+#[deriving(Clone)]
 pub struct MidiTrack {
     track_length: u32,
     events: ~[MidiEvent]
 }
 
+/// `encoding` is how the event was laid out in the file it came from, or None for events made
+/// from scratch. Only the lossless writer pays attention to it.
+#[deriving(Clone)]
 pub struct MidiEvent {
     delta_time: u32,
-    message: MidiMessage
+    message: MidiMessage,
+    encoding: Option<EventEncoding>
+}
+
+/// The choices a file can make when writing an event down, where the spec allows more than one
+/// way of writing the same thing.
+#[deriving(Clone, Eq)]
+pub struct EventEncoding {
+    /// How many 0x80 bytes pad out the front of the delta time.
+    delta_padding: u8,
+    /// Whether the status byte was left out, relying on running status.
+    omit_status: bool,
+    /// How many 0x80 bytes pad out the front of a meta or SysEx event's length.
+    length_padding: u8
+}
+
+/// How to_bytes and write_file lay events out.
+#[deriving(Clone, Eq)]
+pub enum WriteStyle {
+    /// Every status byte written, and every number as short as it can be.
+    Plain,
+    /// Like Plain, but status bytes are left out whenever running status allows it.
+    RunningStatus,
+    /// Everything written the way it was read, so a file from parse_bytes_lossless comes back out
+    /// byte for byte. Events without an encoding, or edited so theirs no longer fits, are written
+    /// Plain.
+    Lossless
 }
 
-/// MIDI files can have one of three formats, defined in the header of the file.
+/// MIDI files can have one of three formats, defined in the header of the file. Format 0 has a
+/// single track, format 1 has tracks that play together, and format 2 has independent tracks
+/// (one pattern or song each) that play one after another.
+#[deriving(Clone, Eq)]
 pub enum FileFormat {
-    SingleTrack = 1,
-    MultipleSynchronous = 2,
-    MultipleAsynchronous = 3
+    SingleTrack = 0,
+    MultipleSynchronous = 1,
+    MultipleAsynchronous = 2
+}
+
+/// What a delta time's 'ticks' are measured in, from the last two bytes of the header. Usually
+/// that's a fraction of a quarter note, so how long a tick lasts depends on the tempo. If the top
+/// bit is set, it's a fraction of an SMPTE frame instead, and the tempo doesn't matter.
+#[deriving(Clone, Eq)]
+pub enum Division {
+    TicksPerQuarter(u16),
+    /// `frames_per_second` is 24, 25, 29 or 30, where 29 really means 29.97 (drop-frame).
+    Smpte { frames_per_second : u8, ticks_per_frame : u8 }
+}
+
+impl Division {
+    /// How many microseconds `ticks` ticks last, given a tempo in microseconds per quarter note.
+    /// SMPTE divisions ignore the tempo, since their ticks are a fixed length.
+    pub fn ticks_to_micros(&self, ticks : u64, tempo : u32) -> u64 {
+        match *self {
+            TicksPerQuarter(tpq) => ticks * (tempo as u64) / (tpq as u64),
+            // 29.97 frames per second is 30000 frames every 1001 seconds.
+            Smpte { frames_per_second : 29, ticks_per_frame : tpf } => {
+                ticks * 1001000000 / (30000 * (tpf as u64))
+            }
+            Smpte { frames_per_second : fps, ticks_per_frame : tpf } => {
+                ticks * 1000000 / ((fps as u64) * (tpf as u64))
+            }
+        }
+    }
 }
 
 /// Not public, this is the contextual data necessary to read a track -- some cases like "running
 /// mode" mean that you need to repeat the previous status. We store the contextual data here.
 struct ContinueTrackRead {
     offset : u32,
-    last_status : u8
+    /// The channel status a data byte would repeat, or 0x00 if there isn't one right now.
+    last_status : u8,
+    /// True when the last F0 SysEx packet didn't end in F7, so the next F7 packet continues it.
+    sysex_open : bool,
+    /// True when reading for parse_bytes_lossless.
+    lossless : bool
 }
 
 /// The various commands a MidiMessage can contain. Codes and descriptions lifted from 
 /// http://www.recordingblogs.com/sa/tabid/88/Default.aspx?topic=Status+byte+(of+a+MIDI+message)
+#[deriving(Clone, Eq)]
 pub enum MidiMessage {
     /// Release a note and stop playing it.
     NoteOff { channel: u8, key : u8, velocity : u8},
@@ -93,8 +196,16 @@ pub enum MidiMessage {
     /// Change a channel pitch up or down.
     PitchWheel { channel : u8, lsb : u8, msb : u8 },
 
-    /// Perform some device specific task.
-    SystemExclusive { amei : u32, nope : u8 },
+    /// Perform some device specific task. In a file this is `F0 <length> <data>`; `data` doesn't
+    /// include the manufacturer ID or the closing F7, and `complete` says whether that F7 was
+    /// there. When it wasn't, the rest follows in SysExContinuation packets.
+    SystemExclusive { manufacturer : ManufacturerId, data : ~[u8], complete : bool },
+    /// The next piece of a SystemExclusive message split across several events, from an F7
+    /// packet. As above, `complete` says whether this piece ends the message.
+    SysExContinuation { data : ~[u8], complete : bool },
+    /// An F7 packet outside of a SystemExclusive message: raw bytes to send as-is, which is how
+    /// files smuggle in things like realtime messages.
+    SysExEscape { data : ~[u8] },
     /// Set the MIDI time to keep in line with some other device.
     MidiTimeCode { message_type : u8, values : u8 },
     /// Cue to a point in the MIDI sequence to be ready to play.
@@ -115,281 +226,785 @@ pub enum MidiMessage {
     ActiveSense,
     /// Reset to default state.
     Reset,
-    /// Not a valid status, repeat previous message, per "running mode," where you can omit a status.
-    InvalidStatus
+
+    /// Information about the file rather than something to play: tempo, names, and so on. Only
+    /// found in MIDI files, where the 0xFF status byte means this instead of Reset.
+    Meta(MetaEvent)
+}
+
+/// Who a SystemExclusive message is for. IDs are one byte, unless the first is 0x00, in which case
+/// the next two bytes are the ID. 0x7E and 0x7F are the "universal" non-realtime and realtime IDs.
+#[deriving(Clone, Eq)]
+pub enum ManufacturerId {
+    ShortId(u8),
+    ExtendedId(u8, u8)
+}
+
+/// The meta events, from the 0xFF status byte. Listed at
+/// http://www.recordingblogs.com/sa/tabid/88/Default.aspx?topic=MIDI+meta+messages
+///
+/// The text events are kept as bytes, since the spec never says what encoding they're in (and
+/// plenty of files, like la_overworld.mid, use Shift-JIS). See `text_to_str`.
+#[deriving(Clone, Eq)]
+pub enum MetaEvent {
+    /// The number of a sequence, in format 2 files or song-select setups.
+    SequenceNumber { number : u16 },
+    /// Any old text.
+    Text { text : ~[u8] },
+    /// A copyright notice.
+    Copyright { text : ~[u8] },
+    /// The name of the sequence in the first track, or of the track anywhere else.
+    TrackName { text : ~[u8] },
+    /// Which instrument the track is meant for.
+    InstrumentName { text : ~[u8] },
+    /// A syllable of lyrics.
+    Lyric { text : ~[u8] },
+    /// A rehearsal mark or section name, like "Verse" or "Loop start".
+    Marker { text : ~[u8] },
+    /// Something happening off-stage at this point, like "curtain opens".
+    CuePoint { text : ~[u8] },
+    /// The name of the program (patch) in use.
+    ProgramName { text : ~[u8] },
+    /// The name of the device the track should be played on.
+    DeviceName { text : ~[u8] },
+    /// Meta and SysEx events that follow are meant for this channel.
+    ChannelPrefix { channel : u8 },
+    /// Events that follow are meant for this MIDI port.
+    PortPrefix { port : u8 },
+    /// Required as the last event of every track.
+    EndOfTrack,
+    /// Microseconds per quarter note from here on.
+    SetTempo { tempo : u32 },
+    /// When the track should start, in SMPTE time. `frame_rate` is the two-bit code packed into
+    /// the hours byte: 0 is 24fps, 1 is 25, 2 is 29.97 (drop-frame) and 3 is 30.
+    SmpteOffset { frame_rate : u8, hours : u8, minutes : u8, seconds : u8, frames : u8, subframes : u8 },
+    /// Time signature. The denominator is stored as a power of two, so 6/8 has a numerator of 6
+    /// and a `denominator_power` of 3.
+    TimeSignature { numerator : u8, denominator_power : u8, clocks_per_click : u8, thirty_seconds_per_quarter : u8 },
+    /// Key signature: negative numbers count flats, positive ones sharps.
+    KeySignature { sharps : i8, minor : bool },
+    /// Data only one particular sequencer cares about.
+    SequencerSpecific { data : ~[u8] },
+    /// Any meta type we don't know, or a known one whose length doesn't match the spec.
+    UnknownMeta { meta_type : u8, data : ~[u8] }
+}
+
+/// Channel 10, where General MIDI keeps the drums and keys pick instruments rather than pitches.
+/// Counting from 0, as MidiMessage does.
+pub static DRUM_CHANNEL : u8 = 9;
+
+impl MidiMessage {
+    /// The channel (0 to 15) a channel message is for, or None for everything else.
+    pub fn channel(&self) -> Option<u8> {
+        match *self {
+            NoteOff         { channel : c, _ } => Some(c),
+            NoteOn          { channel : c, _ } => Some(c),
+            Aftertouch      { channel : c, _ } => Some(c),
+            ControlChange   { channel : c, _ } => Some(c),
+            ProgramChange   { channel : c, _ } => Some(c),
+            ChannelPressure { channel : c, _ } => Some(c),
+            PitchWheel      { channel : c, _ } => Some(c),
+            _ => None
+        }
+    }
+}
+
+impl MidiFile {
+    /// A file made from scratch, with a track for each list of (delta time, message) pairs. See
+    /// `MidiTrack::from_messages`.
+    pub fn from_messages(format : FileFormat, division : Division,
+                         tracks : ~[~[(u32, MidiMessage)]]) -> MidiFile {
+        let tracks = tracks.move_iter().map(|events| MidiTrack::from_messages(events)).collect::<~[MidiTrack]>();
+        MidiFile{ header : MidiHeader{ file_format : format,
+                                       num_tracks : tracks.len() as u16,
+                                       division : division,
+                                       extra : ~[] },
+                  tracks : tracks,
+                  unknown_chunks : ~[],
+                  trailing_bytes : ~[] }
+    }
+}
+
+impl MidiTrack {
+    /// A track made from scratch out of (delta time, message) pairs. Like any track that wasn't
+    /// parsed, its events have no encoding and its length is left at 0.
+    pub fn from_messages(events : ~[(u32, MidiMessage)]) -> MidiTrack {
+        let events = events.move_iter().map(|(delta, message)| {
+            MidiEvent{ delta_time : delta, message : message, encoding : None }
+        }).collect::<~[MidiEvent]>();
+        MidiTrack{ track_length : 0, events : events }
+    }
+
+    /// The name given by the first TrackName meta event, if there is one.
+    pub fn name(&self) -> Option<~str> {
+        for event in self.events.iter() {
+            match event.message {
+                Meta(TrackName { text : ref t }) => { return Some(text_to_str(*t)); }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Every channel with a message in this track, in order.
+    pub fn channels(&self) -> ~[u8] {
+        let mut channels = ~[];
+        for event in self.events.iter() {
+            match event.message.channel() {
+                Some(c) if !channels.contains(&c) => { channels.push(c); }
+                _ => {}
+            }
+        }
+        channels.sort();
+        channels
+    }
+
+    /// A copy of this track with only the channel messages for `channels`. Everything that isn't a
+    /// channel message (meta events, SysEx and so on) stays, and the delta times of anything left
+    /// out are folded into whatever comes next, so the rest keeps its timing. The new events
+    /// don't have an encoding, since they no longer sit where they were read from.
+    pub fn filter_channels(&self, channels : &[u8]) -> MidiTrack {
+        let mut events = ~[];
+        let mut carried = 0u32;
+        for event in self.events.iter() {
+            let keep = match event.message.channel() {
+                Some(c) => channels.contains(&c),
+                None => true
+            };
+            if keep {
+                events.push(MidiEvent{ delta_time : carried + event.delta_time,
+                                       message : event.message.clone(),
+                                       encoding : None });
+                carried = 0;
+            } else {
+                carried += event.delta_time;
+            }
+        }
+        MidiTrack{ track_length : 0, events : events }
+    }
+
+    /// Splits a track into one per channel, paired up with the channel they're for. Format 0
+    /// files keep every instrument in one track, told apart only by channel, so this pulls them
+    /// back apart. Each one keeps all the non-channel events, as `filter_channels` does.
+    pub fn split_channels(&self) -> ~[(u8, MidiTrack)] {
+        self.channels().iter().map(|&c| (c, self.filter_channels([c]))).collect()
+    }
+}
+
+/// Why a MIDI file couldn't be parsed (or, now and then, written).
+#[deriving(Clone, Eq)]
+pub enum MidiErrorKind {
+    /// The file couldn't be read at all. Holds the description from the IO error.
+    ReadFailed(~str),
+    /// The file doesn't start with a standard `MThd` chunk of length 6.
+    BadHeaderChunk,
+    /// The header names a format other than 0, 1 or 2.
+    UnknownFileFormat(u16),
+    /// The header's time division is zero, or an SMPTE rate that doesn't exist.
+    BadDivision(u16),
+    /// A track chunk doesn't start with `MTrk`.
+    BadTrackChunk,
+    /// A status byte that doesn't mean anything in a MIDI file.
+    UnknownStatus(u8),
+    /// The data ends partway through something, or something claims to be longer than the data
+    /// that's left.
+    Truncated,
+    /// A variable-length number (a delta time or a length) that goes on past four bytes. When
+    /// writing, a number too big to fit in four.
+    VarLenTooLong,
+    /// A variable-length number padded out with leading zero groups.
+    OverlongVarLen,
+    /// A SysEx message too short to say who it's for.
+    MissingManufacturerId,
+    /// A data byte where a status byte should be, with no running status for it to repeat.
+    NoRunningStatus,
+    /// The file couldn't be written. Holds the description from the IO error.
+    WriteFailed(~str),
+    /// An event that wouldn't write back out the way it was read, found by a lossless parse.
+    LossyEvent,
+    /// More tracks to write than the header has room to count.
+    TooManyTracks(uint)
+}
+
+/// A parse failure: what went wrong, the byte offset into the file where it was noticed, and the
+/// track it was in (counting from 0), if it was in one. Write failures look the same, with the
+/// offset counting into the output.
+#[deriving(Clone, Eq)]
+pub struct MidiError {
+    kind : MidiErrorKind,
+    offset : u32,
+    track : Option<uint>
+}
+
+impl ToStr for MidiError {
+    fn to_str(&self) -> ~str {
+        let reason = match self.kind {
+            ReadFailed(ref desc) => format!("couldn't read the file ({})", *desc),
+            BadHeaderChunk => ~"no standard MThd header",
+            UnknownFileFormat(f) => format!("unknown file format {}", f),
+            BadDivision(d) => format!("invalid time division {}", d),
+            BadTrackChunk => ~"track doesn't start with MTrk",
+            UnknownStatus(s) => format!("unknown status byte {}", s),
+            Truncated => ~"data ends too soon",
+            VarLenTooLong => ~"variable-length number longer than 4 bytes",
+            OverlongVarLen => ~"variable-length number with needless leading zeros",
+            MissingManufacturerId => ~"SysEx without a manufacturer ID",
+            NoRunningStatus => ~"data byte with no running status to repeat",
+            WriteFailed(ref desc) => format!("couldn't write the file ({})", *desc),
+            LossyEvent => ~"event that can't be written back exactly",
+            TooManyTracks(n) => format!("{} tracks, more than a header can count", n)
+        };
+        match self.track {
+            // Tracks are counted from 1 when talking to people, like pretty_print does.
+            Some(t) => format!("{} at byte {}, in track {}", reason, self.offset, t + 1),
+            None => format!("{} at byte {}", reason, self.offset)
+        }
+    }
+}
+
+/// Every chunk starts with a four-byte ID and a four-byte length.
+static CHUNK_HEADER_LENGTH : u32 = 8;
+
+/// Chains together functions returning Result, handing back the first error it comes across.
+macro_rules! try_parse(
+    ($e:expr) => (match $e { Ok(x) => x, Err(e) => { return Err(e); } })
+)
+
+fn parse_error<T>(kind : MidiErrorKind, offset : u32) -> Result<T, MidiError> {
+    Err(MidiError{ kind : kind, offset : offset, track : None })
+}
+
+/// Reads and parses a MIDI file from disk.
+pub fn parse_file(filename : &str) -> Result<MidiFile, MidiError> {
+    let contents_buf = try_parse!(read_file(filename));
+    parse_bytes(contents_buf)
 }
 
-pub fn parse_file(filename : &str) -> Option<MidiFile> {
+/// Parses a MIDI file that's already been read into memory.
+pub fn parse_bytes(buf : &[u8]) -> Result<MidiFile, MidiError> {
+    parse_buffer(buf, false)
+}
+
+/// Like parse_file, but see parse_bytes_lossless.
+pub fn parse_file_lossless(filename : &str) -> Result<MidiFile, MidiError> {
+    let contents_buf = try_parse!(read_file(filename));
+    parse_bytes_lossless(contents_buf)
+}
+
+/// Parses a MIDI file so that writing it back out with the Lossless style gives exactly the same
+/// bytes, as long as nothing's been changed in between. Edit some events and only those get
+/// re-encoded. To make that promise, this accepts variable-length numbers padded with leading
+/// zeros (which parse_bytes rejects), and fails with LossyEvent on anything it couldn't reproduce,
+/// like a data byte with its top bit set.
+pub fn parse_bytes_lossless(buf : &[u8]) -> Result<MidiFile, MidiError> {
+    parse_buffer(buf, true)
+}
+
+fn read_file(filename : &str) -> Result<~[u8], MidiError> {
     // Open the file according to the filename
     let path = &Path::new(filename);
+    let mut failure = None;
 
-    do io_error::cond.trap(|_| {
+    let contents_buf = do io_error::cond.trap(|e| {
         // error on file IO
-        error!("Issue with file!");
+        failure = Some(e.to_str());
     }).inside {
-        let contents_buf = File::open(path).read_to_end();
-        match parse_header(contents_buf) {
-            Some(header) => {
-                match parse_all_tracks(header, contents_buf) {
-                    Some(tracks) => {
-                        let new_midifile = MidiFile{header: header, tracks : tracks};
-                        Some(new_midifile)
-                    }
-                    None => { None }
-                } // match parse_all_tracks
-            }
-            None => { None }
-        } // match parse_header
+        File::open(path).read_to_end()
+    };
+    match failure {
+        Some(desc) => parse_error(ReadFailed(desc), 0),
+        None => Ok(contents_buf)
     }
 }
 
+fn parse_buffer(buf : &[u8], lossless : bool) -> Result<MidiFile, MidiError> {
+    let header = try_parse!(parse_header(buf));
+    let (tracks, unknown_chunks, trailing_bytes) = try_parse!(parse_all_tracks(&header, buf, lossless));
+    Ok(MidiFile{header: header,
+                tracks : tracks,
+                unknown_chunks : unknown_chunks,
+                trailing_bytes : trailing_bytes})
+}
 
-/// Parses the first 14 bytes, which comprise a MIDI header.
-fn parse_header(buf : &[u8]) -> Option<MidiHeader> {
-    let err = buf[0] != ('M' as u8) || buf[1] != ('T' as u8)
-           || buf[2] != ('h' as u8) || buf[3] != ('d' as u8)
-           || buf[4] != 0           || buf[5] != 0
-           || buf[6] != 0           || buf[7] != 6;
-
-    if err {
-        error!("Malformed MIDI header -- first 8 bytes nonstandard.");
-        None
-    } else {
-        let ff = u16_from_u8_at(buf, 8);
-        let num_tracks = u16_from_u8_at(buf, 10);
-        let ticks_per_quarter = u16_from_u8_at(buf, 12);
+/// Iterates over the chunks in `buf`, header included, giving back each one's ID and data. If a
+/// chunk runs off the end of the buffer, that comes back as an error and the iteration stops.
+pub fn chunks<'a>(buf : &'a [u8]) -> Chunks<'a> {
+    Chunks{ buf : buf, offset : 0, failed : false }
+}
 
-        match file_format_from_u16(ff) {
-            Some(x) => { Some(MidiHeader{file_format : x,
-                                         num_tracks : num_tracks,
-                                         ticks_per_quarter : ticks_per_quarter}) }
-            None => {
-                error!("Invalid file format in header.");
-                None
+impl<'a> Iterator<Result<RawChunk<'a>, MidiError>> for Chunks<'a> {
+    fn next(&mut self) -> Option<Result<RawChunk<'a>, MidiError>> {
+        if self.failed || (self.offset as uint) >= self.buf.len() {
+            return None;
+        }
+        match read_chunk(self.buf, self.offset) {
+            Ok(chunk) => {
+                self.offset = chunk.offset + CHUNK_HEADER_LENGTH + (chunk.data.len() as u32);
+                Some(Ok(chunk))
+            }
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
             }
         }
     }
 }
 
-/// Parses all the tracks in a MIDI file, read into a buffer.
+fn read_chunk<'a>(buf : &'a [u8], offset : u32) -> Result<RawChunk<'a>, MidiError> {
+    let chunk_header = try_parse!(slice_at(buf, offset, CHUNK_HEADER_LENGTH));
+    let length = try_parse!(u32_from_u8_at(buf, offset + 4));
+    let data = try_parse!(slice_at(buf, offset + CHUNK_HEADER_LENGTH, length));
+    Ok(RawChunk{ id : chunk_header.slice_to(4), offset : offset, data : data })
+}
+
+
+/// Parses the header chunk at the start of the buffer. That's normally 14 bytes, but the spec
+/// allows for the header to grow, so anything past the standard six bytes of data is kept aside.
+fn parse_header(buf : &[u8]) -> Result<MidiHeader, MidiError> {
+    if buf.len() < 14 {
+        return parse_error(Truncated, buf.len() as u32);
+    }
+    let err = buf[0] != ('M' as u8) || buf[1] != ('T' as u8)
+           || buf[2] != ('h' as u8) || buf[3] != ('d' as u8);
+    let length = try_parse!(u32_from_u8_at(buf, 4));
+
+    if err || length < 6 {
+        return parse_error(BadHeaderChunk, 0);
+    }
+    let extra = try_parse!(slice_at(buf, 14, length - 6));
+
+    let ff = try_parse!(u16_from_u8_at(buf, 8));
+    let num_tracks = try_parse!(u16_from_u8_at(buf, 10));
+    let division = try_parse!(u16_from_u8_at(buf, 12));
+
+    match (file_format_from_u16(ff), division_from_u16(division)) {
+        (Some(x), Some(d)) => { Ok(MidiHeader{file_format : x,
+                                              num_tracks : num_tracks,
+                                              division : d,
+                                              extra : extra.to_owned()}) }
+        (None, _) => parse_error(UnknownFileFormat(ff), 8),
+        (_, None) => parse_error(BadDivision(division), 12)
+    }
+}
+
+/// Parses all the tracks in a MIDI file, read into a buffer, setting aside any chunks that aren't
+/// tracks. Once all the tracks the header promised have turned up, anything left that doesn't
+/// look like a chunk is set aside too, since plenty of files have junk on the end.
 // TODO: This is a good candidate for parallel calls, rather than sequential.
-fn parse_all_tracks(header : MidiHeader, buf : &[u8]) -> Option<~[MidiTrack]> {
-    // Since the header is always constant size, we begin from 14.
-    let mut offset = 14;
+fn parse_all_tracks(header : &MidiHeader, buf : &[u8], lossless : bool)
+                    -> Result<(~[MidiTrack], ~[UnknownChunk], ~[u8]), MidiError> {
     let mut return_vec = with_capacity(header.num_tracks as uint);
-    let mut error = false;
-
-    for _ in range(0, header.num_tracks) {
-        match parse_track(buf, offset) {
-            Some(track) => {
-                let length = track.track_length;
-                return_vec = append_one(return_vec, track);
-                // the '8' is for the header. Make a constant at top-level?
-                offset += (length + 8);
+    let mut unknown = ~[];
+    // Where the last good chunk ended, starting with the header.
+    let mut end = CHUNK_HEADER_LENGTH + 6 + (header.extra.len() as u32);
+
+    // The first chunk is the header, which we already have.
+    for chunk in chunks(buf).skip(1) {
+        let i = return_vec.len();
+        let chunk = match chunk {
+            Ok(c) => c,
+            Err(e) => {
+                if i >= header.num_tracks as uint {
+                    break;
+                }
+                return Err(MidiError{ track : Some(i), ..e });
             }
-            None => {
-                error = true;
+        };
+
+        end = chunk.offset + CHUNK_HEADER_LENGTH + (chunk.data.len() as u32);
+        if chunk.id == bytes!("MTrk") {
+            match parse_track(buf, chunk.offset, lossless) {
+                Ok(track) => {
+                    return_vec = append_one(return_vec, track);
+                }
+                Err(e) => {
+                    return Err(MidiError{ track : Some(i), ..e });
+                }
             }
+        } else {
+            unknown.push(UnknownChunk{ id : chunk.id.to_owned(),
+                                       data : chunk.data.to_owned(),
+                                       after_track : i });
         }
     }
-    if error {
-        None
-    } else {
-        Some(return_vec)
+
+    if return_vec.len() < header.num_tracks as uint {
+        return Err(MidiError{ kind : Truncated,
+                              offset : buf.len() as u32,
+                              track : Some(return_vec.len()) });
     }
+    Ok((return_vec, unknown, buf.slice_from(end as uint).to_owned()))
 }
 
 /// Parses an individual track beginning at the specified offset.
-fn parse_track(buf : &[u8], offset : u32) -> Option<MidiTrack> {
+fn parse_track(buf : &[u8], offset : u32, lossless : bool) -> Result<MidiTrack, MidiError> {
+    // The chunk ID and length have to be there before we can look at either.
+    let chunk_header = try_parse!(slice_at(buf, offset, CHUNK_HEADER_LENGTH));
     // chunk ID (4 bytes of MTrk)
-    let err = buf[0] != ('M' as u8) || buf[1] != ('T' as u8)
-           || buf[2] != ('r' as u8) || buf[3] != ('k' as u8);
+    let err = chunk_header[0] != ('M' as u8) || chunk_header[1] != ('T' as u8)
+           || chunk_header[2] != ('r' as u8) || chunk_header[3] != ('k' as u8);
     if err {
-        error!("Malformed MIDI header -- first 4 bytes nonstandard, offset is {}", offset);
-        None
-    } else {
-        let track_size = get_track_size(buf, offset);
-        let event_offset = offset + 8;
-        let mut midi_events = with_capacity(0);
-        let mut error = false;
-        let mut cont = ContinueTrackRead { offset : event_offset, last_status : 0x00 };
-        // Parse events in sequence.
-        while cont.offset < (event_offset + track_size) {
-            match parse_event(buf, cont) {
-                None => {
-                    error!("Malformed event, somewhere near offset {}", event_offset);
-                    error = true;
-                    break;
-                }
-                Some((x, new_cont)) => {
-                    midi_events = append_one(midi_events, x);
-                    cont = new_cont;
-                }
-            }
+        return parse_error(BadTrackChunk, offset);
+    }
+
+    let track_size = try_parse!(get_track_size(buf, offset));
+    let event_offset = offset + CHUNK_HEADER_LENGTH;
+    let track_end = try_parse!(checked_end(buf, event_offset, track_size));
+    // Events are read from a slice that stops where the track does, so a bad event can't wander
+    // off into the next chunk. Offsets still count from the start of the file.
+    let track_buf = buf.slice_to(track_end as uint);
+    let mut midi_events = with_capacity(0);
+    let mut cont = ContinueTrackRead { offset : event_offset,
+                                       last_status : 0x00,
+                                       sysex_open : false,
+                                       lossless : lossless };
+    // Parse events in sequence.
+    while cont.offset < track_end {
+        let (x, new_cont) = try_parse!(parse_event(track_buf, cont));
+        midi_events = append_one(midi_events, x);
+        cont = new_cont;
+    }
+    Ok(MidiTrack{ track_length : track_size, events : midi_events })
+}
+
+fn parse_event(buf : &[u8], cont : ContinueTrackRead) -> Result<(MidiEvent, ContinueTrackRead), MidiError> {
+    let (ticks, message_offset) = try_parse!(read_padded_vlq(buf, cont.offset));
+    let delta_padding = vlq_padding(buf, cont.offset);
+    if delta_padding > 0 && !cont.lossless {
+        return parse_error(OverlongVarLen, cont.offset);
+    }
+    let (message, new_offset) = try_parse!(parse_message(buf, message_offset, cont.last_status));
+    // Meta events and SysEx are the ones with lengths, just after the type byte or the status.
+    let length_offset = match message {
+        Meta(_) => Some(message_offset + 2),
+        SystemExclusive {_} | SysExEscape {_} => Some(message_offset + 1),
+        _ => None
+    };
+    let length_padding = match length_offset {
+        Some(o) => vlq_padding(buf, o),
+        None => 0
+    };
+    if length_padding > 0 && !cont.lossless {
+        return parse_error(OverlongVarLen, length_offset.unwrap());
+    }
+    let encoding = EventEncoding{ delta_padding : delta_padding,
+                                  omit_status : !msb_is_one(buf[message_offset]),
+                                  length_padding : length_padding };
+
+    // An F7 packet carries on an unfinished SysEx if there is one, and is an
+    // escape otherwise. parse_message can't tell, so we sort it out here.
+    let message = if cont.sysex_open { continue_sysex(message) } else { message };
+    let sysex_open = match message {
+        SystemExclusive { complete : c, _ } => !c,
+        SysExContinuation { complete : c, _ } => !c,
+        _ => cont.sysex_open
+    };
+    let event = MidiEvent{ delta_time : ticks, message : message, encoding : Some(encoding) };
+
+    // Rather than trust every corner of the parser and writer to agree, a lossless parse writes
+    // each event straight back out and checks it got the same bytes.
+    if cont.lossless {
+        let mut written = ~[];
+        let same = match write_event(&event, cont.last_status, Lossless, &mut written, 0) {
+            Ok(_) => written.as_slice() == buf.slice(cont.offset as uint, new_offset as uint),
+            Err(_) => false
+        };
+        if !same {
+            return parse_error(LossyEvent, cont.offset);
         }
-        match error {
-            false => Some(MidiTrack{ track_length : track_size, events : midi_events }),
-            true => None
+    }
+
+    let status = running_status_after(&event.message, cont.last_status);
+    Ok((event,
+        ContinueTrackRead{ offset : new_offset,
+                           last_status : status,
+                           sysex_open : sysex_open,
+                           lossless : cont.lossless }))
+}
+
+/// What running status is once `message` has been read. Channel messages set it and realtime
+/// messages leave it alone. System common messages cancel it, and the file format spec says SysEx
+/// and meta events do too.
+fn running_status_after(message : &MidiMessage, last_status : u8) -> u8 {
+    match *message {
+        // Meta events share 0xFF with Reset, but aren't realtime messages.
+        Meta(_) => 0x00,
+        _ => {
+            let status = get_status_byte(message);
+            if is_channel_status(status) {
+                status
+            } else if is_realtime_status(status) {
+                last_status
+            } else {
+                0x00
+            }
         }
     }
 }
 
-fn parse_event(buf : &[u8], cont : ContinueTrackRead) -> Option<(MidiEvent, ContinueTrackRead)> {
-    match parse_ticks(buf, cont.offset) {
-        (ticks, new_offset) => {
-           match parse_message(buf, new_offset, cont.last_status) {
-               None => { None }
-               Some((message, new_offset)) => { 
-                    Some((MidiEvent{ delta_time : ticks, message : message },
-                         ContinueTrackRead{ offset : new_offset, last_status : get_status_byte(message) }))
-               }
-           }
+/// Turns an F7 packet into the next piece of an unfinished SysEx. Anything else passes through.
+fn continue_sysex(message : MidiMessage) -> MidiMessage {
+    match message {
+        SysExEscape { data : d } => {
+            let complete = d.len() > 0 && d[d.len() - 1] == 0xF7;
+            let body = if complete { d.slice_to(d.len() - 1).to_owned() } else { d };
+            SysExContinuation { data : body, complete : complete }
         }
+        other => other
     }
 }
 
-// MIDI spec says length should be at most 4 bytes, so some hardcoded values here. Should probably
-// have more safety bits than the simple assert.
-// 
-// A small reminder of how MIDI Events work: you start with the number of ticks, followed by a MIDI
-// message. This function parses the ticks, which is variable length.
-//
-// The number of ticks can be expressed with at least 1 and at most 4 bytes. All bytes must have a
-// '1' in the highest order position, except the last, which must have a 0. When you've read all the
-// bytes, you combine the bottom 7 of all of them into one long bitstring, then evaluate it for the
-// number of ticks.
-//
-// The code is a little hairy since making a bunch of 8-bit bytes into 7-bit bytes to combine to
-// some bitstring that is a multiple of 7... maybe my bitflip-foo isn't so good, but it's hard to
-// find a way to do it easily and 'elegantly.' Instead, I store them all into 32-bit values, and
-// once it's established how many there are (from time_offset) I combine them together by
-// bitshift + OR.
-fn parse_ticks(buf : &[u8], offset : u32) -> (u32, u32) {
-    let mut time_offset = 0;
-    let mut time_buffer : [u32, ..4] = [0,0,0,0];
-    let mut return_value;
-    loop {
-        assert!(time_offset < 4);
-        let curr = buf[offset + time_offset];
-        time_buffer[time_offset] = (lower_seven_bits(curr) as u32);
-        if msb_is_one(curr) {
-            time_offset += 1;
-        } else {
-            let mut loop_offset = 0;
-            let mut time_ticks = 0;
-            while loop_offset <= time_offset {
-                let byte_correction = time_offset - loop_offset;
-                let contribution = (time_buffer[0 + loop_offset] << (8 * byte_correction)) >> byte_correction;
-                time_ticks = time_ticks | contribution;
-                loop_offset += 1;
-            }
-            return_value = (time_ticks, offset + time_offset + 1);
-            break;
+/// The largest number a variable-length quantity can hold: 28 bits, in four bytes.
+pub static MAX_VLQ : u32 = 0x0FFFFFFF;
+
+/// Reads a variable-length quantity, the way delta times and the lengths of meta and SysEx events
+/// are stored. Returns the number and the offset just past it.
+///
+/// A small reminder of how these work: the number is split into 7-bit groups, most significant
+/// first, one group per byte. Every byte but the last has its top bit set. The spec allows at most
+/// 4 bytes, so 28 bits. Leading groups of zero (a first byte of 0x80) would make the same number
+/// longer than it needs to be; the spec doesn't allow that either, so it's an error here.
+pub fn read_vlq(buf : &[u8], offset : u32) -> Result<(u32, u32), MidiError> {
+    if try_parse!(byte_at(buf, offset)) == 0x80 {
+        return parse_error(OverlongVarLen, offset);
+    }
+    read_padded_vlq(buf, offset)
+}
+
+/// Like read_vlq, but lets the leading zero groups through. The parser reads everything this way
+/// and decides afterwards whether to complain, since lossless parsing has to keep them.
+fn read_padded_vlq(buf : &[u8], offset : u32) -> Result<(u32, u32), MidiError> {
+    let mut value = 0u32;
+    for i in range(0u32, 4) {
+        let byte = try_parse!(byte_at(buf, offset + i));
+        value = (value << 7) | (lower_seven_bits(byte) as u32);
+        if !msb_is_one(byte) {
+            return Ok((value, offset + i + 1));
         }
     }
-    return_value
+    parse_error(VarLenTooLong, offset)
+}
+
+/// How many leading zero groups pad out the variable-length quantity at `offset`, which has to
+/// have been read successfully already.
+fn vlq_padding(buf : &[u8], offset : u32) -> u8 {
+    let mut padding = 0u8;
+    while padding < 3 && buf[offset + (padding as u32)] == 0x80 {
+        padding += 1;
+    }
+    padding
+}
+
+/// Writes `value` as a variable-length quantity into `out`, returning how many bytes it took.
+/// Values above MAX_VLQ don't fit, and give back 0 without touching `out`.
+pub fn write_vlq(value : u32, out : &mut [u8, ..4]) -> uint {
+    if value > MAX_VLQ {
+        return 0;
+    }
+    let mut length = 1u;
+    while length < 4 && (value >> (7 * length)) != 0 {
+        length += 1;
+    }
+    for i in range(0, length) {
+        let shift = 7 * (length - 1 - i);
+        let group = ((value >> shift) & 0x7F) as u8;
+        out[i] = if i + 1 < length { group | 0x80 } else { group };
+    }
+    length
+}
+
+/// Encodes `value` as a variable-length quantity, or None if it's bigger than MAX_VLQ.
+pub fn encode_vlq(value : u32) -> Option<~[u8]> {
+    let mut bytes = [0u8, ..4];
+    match write_vlq(value, &mut bytes) {
+        0 => None,
+        length => Some(bytes.slice_to(length).to_owned())
+    }
 }
 
-fn parse_message(buf : &[u8], start_offset : u32, last_status : u8) -> Option<(MidiMessage, u32)> {
+fn parse_message(buf : &[u8], start_offset : u32, last_status : u8) -> Result<(MidiMessage, u32), MidiError> {
 
-    let mut status_byte;
-    let mut data_offset;
-    if is_invalid_status_byte(buf[start_offset]) {
-        status_byte = last_status;
-        data_offset = start_offset;
+    let first_byte = try_parse!(byte_at(buf, start_offset));
+    // A data byte where the status should be means "running status": the status was left out, and
+    // the last channel message's is repeated. That only works if there is one to repeat.
+    let (status_byte, data_offset) = if msb_is_one(first_byte) {
+        (first_byte, start_offset + 1)
+    } else if is_channel_status(last_status) {
+        (last_status, start_offset)
     } else {
-        status_byte = buf[start_offset];
-        data_offset = start_offset + 1;
+        return parse_error(NoRunningStatus, start_offset);
+    };
+
+    if status_byte == 0xFF {
+        return parse_meta(buf, data_offset);
+    }
+    if status_byte == 0xF0 || status_byte == 0xF7 {
+        return parse_sysex(buf, data_offset, status_byte);
     }
 
     let status_pattern = status_byte & 0xF0;
     let channel_number = status_byte & 0x0F;
     match status_pattern {
         0x80 => {
-            let k = lower_seven_bits(buf[data_offset]);
-            let v = lower_seven_bits(buf[data_offset + 1]);
-            Some((NoteOff{ channel : channel_number, key : k, velocity : v }, data_offset + 2))
+            let k = try_parse!(data_byte_at(buf, data_offset));
+            let v = try_parse!(data_byte_at(buf, data_offset + 1));
+            Ok((NoteOff{ channel : channel_number, key : k, velocity : v }, data_offset + 2))
         }
         0x90 => {
-            let k = lower_seven_bits(buf[data_offset]);
-            let v = lower_seven_bits(buf[data_offset + 1]);
-            Some((NoteOn{ channel : channel_number, key : k, velocity : v }, data_offset + 2))
+            let k = try_parse!(data_byte_at(buf, data_offset));
+            let v = try_parse!(data_byte_at(buf, data_offset + 1));
+            Ok((NoteOn{ channel : channel_number, key : k, velocity : v }, data_offset + 2))
         }
         0xA0 => {
-            let k = lower_seven_bits(buf[data_offset]);
-            let v = lower_seven_bits(buf[data_offset + 1]);
-            Some((Aftertouch{ channel : channel_number, key : k, velocity : v }, data_offset + 2))
+            let k = try_parse!(data_byte_at(buf, data_offset));
+            let v = try_parse!(data_byte_at(buf, data_offset + 1));
+            Ok((Aftertouch{ channel : channel_number, key : k, velocity : v }, data_offset + 2))
         }
         0xB0 => {
-            let c = lower_seven_bits(buf[data_offset]);
-            let v = lower_seven_bits(buf[data_offset + 1]);
-            Some((ControlChange{ channel : channel_number, controller : c, value : v }, data_offset + 2))
+            let c = try_parse!(data_byte_at(buf, data_offset));
+            let v = try_parse!(data_byte_at(buf, data_offset + 1));
+            Ok((ControlChange{ channel : channel_number, controller : c, value : v }, data_offset + 2))
         }
         0xC0 => {
-            let p = lower_seven_bits(buf[data_offset]);
-            Some((ProgramChange{ channel : channel_number, new_program : p }, data_offset + 1))
+            let p = try_parse!(data_byte_at(buf, data_offset));
+            Ok((ProgramChange{ channel : channel_number, new_program : p }, data_offset + 1))
         }
         0xD0 => {
-            let v = lower_seven_bits(buf[data_offset]);
-            Some((ChannelPressure{ channel : channel_number, value : v }, data_offset + 1))
+            let v = try_parse!(data_byte_at(buf, data_offset));
+            Ok((ChannelPressure{ channel : channel_number, value : v }, data_offset + 1))
         }
         0xE0 => {
-            let l = lower_seven_bits(buf[data_offset]);
-            let m = lower_seven_bits(buf[data_offset + 1]);
-            Some((PitchWheel{ channel : channel_number, lsb : l, msb : m }, data_offset + 2))
+            let l = try_parse!(data_byte_at(buf, data_offset));
+            let m = try_parse!(data_byte_at(buf, data_offset + 1));
+            Ok((PitchWheel{ channel : channel_number, lsb : l, msb : m }, data_offset + 2))
         }
-        0xF0 => {
+        // Only 0xF0 is left: the system messages, where the low nibble is the message type.
+        _ => {
             match channel_number {
-                0x00 => {
-                    // We don't support MIDI with system exclusive commands, can't even parse it
-                    // since you don't know whether the AMEI is one or three bytes, nor do you know
-                    // the length of what follows.
-                    None
-                }
                 0x01 => {
-                    let mt = lower_seven_bits(buf[data_offset]);
-                    let v = lower_seven_bits(buf[data_offset + 1]);
-                    Some((MidiTimeCode{ message_type : mt, values : v }, data_offset + 2))
+                    let mt = try_parse!(data_byte_at(buf, data_offset));
+                    let v = try_parse!(data_byte_at(buf, data_offset + 1));
+                    Ok((MidiTimeCode{ message_type : mt, values : v }, data_offset + 2))
                 }
                 0x02 => {
-                    let l = lower_seven_bits(buf[data_offset]);
-                    let m = lower_seven_bits(buf[data_offset + 1]);
-                    Some((SongPositionPointer{ lsb : l, msb : m }, data_offset + 2))
+                    let l = try_parse!(data_byte_at(buf, data_offset));
+                    let m = try_parse!(data_byte_at(buf, data_offset + 1));
+                    Ok((SongPositionPointer{ lsb : l, msb : m }, data_offset + 2))
                 }
                 0x03 => {
-                    let s = lower_seven_bits(buf[data_offset]);
-                    Some((SongSelect{ song : s }, data_offset + 1))
+                    let s = try_parse!(data_byte_at(buf, data_offset));
+                    Ok((SongSelect{ song : s }, data_offset + 1))
                 }
                 0x06 => {
-                    Some((TuneRequest, data_offset))
+                    Ok((TuneRequest, data_offset))
                 }
                 0x08 => {
-                    Some((MidiClock, data_offset))
+                    Ok((MidiClock, data_offset))
                 }
                 0x0A => {
-                    Some((MidiStart, data_offset))
+                    Ok((MidiStart, data_offset))
                 }
                 0x0B => {
-                    Some((MidiContinue, data_offset))
+                    Ok((MidiContinue, data_offset))
                 }
                 0x0C => {
-                    Some((MidiStop, data_offset))
+                    Ok((MidiStop, data_offset))
                 }
                 0x0E => {
-                    Some((ActiveSense, data_offset))
+                    Ok((ActiveSense, data_offset))
                 }
                 0x0F => {
-                    Some((Reset, data_offset))
+                    Ok((Reset, data_offset))
                 }
-                _ => { None }
+                // 0xF4, 0xF5, 0xF9 and 0xFD are undefined.
+                _ => { parse_error(UnknownStatus(status_byte), start_offset) }
             }
         }
-        _ => {
-            Some((InvalidStatus, data_offset))
-        }
     }
 }
 
+/// Parses a SysEx packet, starting just after its F0 or F7. In a file these look like
+///
+///     F0 <length> <manufacturer ID> <data> [F7]
+///     F7 <length> <data>
+///
+/// with the length stored like delta times. F7 packets all come back as SysExEscape; parse_event
+/// knows whether they're really continuing an earlier F0.
+fn parse_sysex(buf : &[u8], offset : u32, status : u8) -> Result<(MidiMessage, u32), MidiError> {
+    let (length, data_offset) = try_parse!(read_padded_vlq(buf, offset));
+    let packet = try_parse!(slice_at(buf, data_offset, length));
+    let end = data_offset + length;
+    if status == 0xF7 {
+        return Ok((SysExEscape { data : packet.to_owned() }, end));
+    }
+
+    let complete = packet.len() > 0 && packet[packet.len() - 1] == 0xF7;
+    let body = if complete { packet.slice_to(packet.len() - 1) } else { packet };
+    if body.len() >= 1 && body[0] != 0x00 {
+        Ok((SystemExclusive { manufacturer : ShortId(body[0]),
+                              data : body.slice_from(1).to_owned(),
+                              complete : complete }, end))
+    } else if body.len() >= 3 {
+        Ok((SystemExclusive { manufacturer : ExtendedId(body[1], body[2]),
+                              data : body.slice_from(3).to_owned(),
+                              complete : complete }, end))
+    } else {
+        parse_error(MissingManufacturerId, data_offset)
+    }
+}
+
+/// Parses a meta event, starting just after its 0xFF. These look like
+///
+///     FF <type> <length> <data>
+///
+/// where the length is a variable-length number, stored just like delta times.
+fn parse_meta(buf : &[u8], offset : u32) -> Result<(MidiMessage, u32), MidiError> {
+    let meta_type = try_parse!(byte_at(buf, offset));
+    let (length, data_offset) = try_parse!(read_padded_vlq(buf, offset + 1));
+    let data = try_parse!(slice_at(buf, data_offset, length));
+    Ok((Meta(meta_from_data(meta_type, data)), data_offset + length))
+}
+
+/// Turns a meta event's type and data into a MetaEvent. Anything that doesn't look the way the
+/// spec says it should is kept as an UnknownMeta, so no data is thrown away.
+fn meta_from_data(meta_type : u8, data : &[u8]) -> MetaEvent {
+    match (meta_type, data.len()) {
+        (0x00, 2) => SequenceNumber { number : (data[0] as u16 << 8) | (data[1] as u16) },
+        (0x01, _) => Text { text : data.to_owned() },
+        (0x02, _) => Copyright { text : data.to_owned() },
+        (0x03, _) => TrackName { text : data.to_owned() },
+        (0x04, _) => InstrumentName { text : data.to_owned() },
+        (0x05, _) => Lyric { text : data.to_owned() },
+        (0x06, _) => Marker { text : data.to_owned() },
+        (0x07, _) => CuePoint { text : data.to_owned() },
+        (0x08, _) => ProgramName { text : data.to_owned() },
+        (0x09, _) => DeviceName { text : data.to_owned() },
+        (0x20, 1) => ChannelPrefix { channel : data[0] },
+        (0x21, 1) => PortPrefix { port : data[0] },
+        (0x2F, 0) => EndOfTrack,
+        (0x51, 3) => SetTempo { tempo : (data[0] as u32 << 16) | (data[1] as u32 << 8) | (data[2] as u32) },
+        (0x54, 5) if data[0] & 0x80 == 0 => SmpteOffset { frame_rate : (data[0] >> 5) & 0x03,
+                                                           hours : data[0] & 0x1F,
+                                                           minutes : data[1],
+                                                           seconds : data[2],
+                                                           frames : data[3],
+                                                           subframes : data[4] },
+        (0x58, 4) => TimeSignature { numerator : data[0],
+                                     denominator_power : data[1],
+                                     clocks_per_click : data[2],
+                                     thirty_seconds_per_quarter : data[3] },
+        (0x59, 2) if data[1] <= 1 => KeySignature { sharps : data[0] as i8, minor : data[1] == 1 },
+        (0x7F, _) => SequencerSpecific { data : data.to_owned() },
+        _ => UnknownMeta { meta_type : meta_type, data : data.to_owned() }
+    }
+}
+
+/// Text in meta events is just bytes. Treat them as Latin-1, which at least never fails and reads
+/// fine for the usual ASCII.
+pub fn text_to_str(text : &[u8]) -> ~str {
+    text.iter().map(|&b| b as char).collect::<~str>()
+}
+
 // Pretty-print
 pub fn pretty_print(file : MidiFile) {
     println!("----- MIDI FILE -----");
@@ -397,11 +1012,25 @@ pub fn pretty_print(file : MidiFile) {
 
     let format = file.header.file_format;
     let num_tracks = file.header.num_tracks;
-    let tpq = file.header.ticks_per_quarter;
+    let division = file.header.division;
 
     println!("  File format: {}", file_format_to_string(format));
     println!("  Number of tracks: {}", num_tracks);
-    println!("  Ticks per quarter note: {}", tpq);
+    match division {
+        TicksPerQuarter(tpq) => {
+            println!("  Ticks per quarter note: {}", tpq);
+        }
+        Smpte { frames_per_second : 29, ticks_per_frame : tpf } => {
+            println!("  SMPTE, 29.97 frames per second, {} ticks per frame", tpf);
+        }
+        Smpte { frames_per_second : fps, ticks_per_frame : tpf } => {
+            println!("  SMPTE, {} frames per second, {} ticks per frame", fps, tpf);
+        }
+    }
+
+    if file.header.extra.len() > 0 {
+        println!("  Extra header bytes: {}", file.header.extra.len());
+    }
 
     println!("*****\nTracks:");
 
@@ -412,23 +1041,30 @@ pub fn pretty_print(file : MidiFile) {
         for event in track.events.iter() {
             println!("    --");
             println!("    Delta time: {}", event.delta_time); 
-            println!("    Message: {}", message_to_string(event.message));
+            println!("    Message: {}", message_to_string(&event.message));
         }
         track_number += 1;
     }
+
+    if file.unknown_chunks.len() > 0 {
+        println!("*****\nOther chunks:");
+        for chunk in file.unknown_chunks.iter() {
+            println!("  {} -- {} bytes, after {} tracks", text_to_str(chunk.id), chunk.data.len(), chunk.after_track);
+        }
+    }
     println!("---------------------");
 }
 
 fn file_format_to_string(f : FileFormat) -> ~str {
     match f {
         SingleTrack => format!("Single Track"),
-        MultipleSynchronous => format!("Multiple track, asynchronous"),
-        MultipleAsynchronous => format!("Multiple track, synchronous")
+        MultipleSynchronous => format!("Multiple track, synchronous"),
+        MultipleAsynchronous => format!("Multiple track, asynchronous")
     }
 }
 
-fn message_to_string(m : MidiMessage) -> ~str {
-    match m {
+fn message_to_string(m : &MidiMessage) -> ~str {
+    match *m {
         NoteOff         { channel : c, key : k, velocity : v } => { format!("NoteOff -- channel: {}, key: {}, velocity: {}", c, k, v) }
         NoteOn          { channel : c, key : k, velocity : v } => { format!("NoteOn -- channel: {}, key: {}, velocity: {}", c, k, v) }
         Aftertouch      { channel : c, key : k, velocity : v } => { format!("Aftertouch -- channel: {}, key: {}, velocity: {}", c, k, v) }
@@ -437,7 +1073,17 @@ fn message_to_string(m : MidiMessage) -> ~str {
         ChannelPressure { channel : c, value : v } => { format!("ChannelPressure -- channel: {}, value: {}", c, v) }
         PitchWheel      { channel : c,  lsb : l, msb : m } => { format!("PitchWheel -- channel: {}, lsb: {}, msb: {}", c, l, m) }
 
-        SystemExclusive     {_} => { format!("SystemExclusive") }
+        SystemExclusive { manufacturer : m, data : ref d, complete : c } => {
+            let id = match m {
+                ShortId(a) => format!("{}", a),
+                ExtendedId(a, b) => format!("0 {} {}", a, b)
+            };
+            format!("SystemExclusive -- manufacturer: {}, {} bytes{}", id, d.len(), if c { "" } else { ", continued" })
+        }
+        SysExContinuation { data : ref d, complete : c } => {
+            format!("SysExContinuation -- {} bytes{}", d.len(), if c { "" } else { ", continued" })
+        }
+        SysExEscape { data : ref d } => { format!("SysExEscape -- {} bytes", d.len()) }
         MidiTimeCode        {_} => { format!("MidiTimeCode") }
         SongPositionPointer {_} => { format!("SongPositionPointer") }
         SongSelect          {_} => { format!("SongSelect") }
@@ -448,9 +1094,41 @@ fn message_to_string(m : MidiMessage) -> ~str {
         MidiStop                => { format!("Midi Stop") }
         ActiveSense             => { format!("Active Sense") }
         Reset                   => { format!("Reset") }
-        // InvalidStatus gets an invalid Midi Message, but only for completeness.
-        // Should never happen.
-        _ => { format!("Failed to match message.") }
+        Meta(ref meta)          => { format!("Meta -- {}", meta_to_string(meta)) }
+    }
+}
+
+fn meta_to_string(m : &MetaEvent) -> ~str {
+    match *m {
+        SequenceNumber { number : n } => { format!("SequenceNumber: {}", n) }
+        Text           { text : ref t } => { format!("Text: {}", text_to_str(*t)) }
+        Copyright      { text : ref t } => { format!("Copyright: {}", text_to_str(*t)) }
+        TrackName      { text : ref t } => { format!("TrackName: {}", text_to_str(*t)) }
+        InstrumentName { text : ref t } => { format!("InstrumentName: {}", text_to_str(*t)) }
+        Lyric          { text : ref t } => { format!("Lyric: {}", text_to_str(*t)) }
+        Marker         { text : ref t } => { format!("Marker: {}", text_to_str(*t)) }
+        CuePoint       { text : ref t } => { format!("CuePoint: {}", text_to_str(*t)) }
+        ProgramName    { text : ref t } => { format!("ProgramName: {}", text_to_str(*t)) }
+        DeviceName     { text : ref t } => { format!("DeviceName: {}", text_to_str(*t)) }
+        ChannelPrefix  { channel : c } => { format!("ChannelPrefix: {}", c) }
+        PortPrefix     { port : p } => { format!("PortPrefix: {}", p) }
+        EndOfTrack                  => { format!("EndOfTrack") }
+        SetTempo       { tempo : t } => { format!("SetTempo: {} microseconds per quarter note", t) }
+        SmpteOffset    { hours : h, minutes : m, seconds : s, frames : f, subframes : sf, _ } => {
+            format!("SmpteOffset: {}:{}:{}, frame {}.{}", h, m, s, f, sf)
+        }
+        TimeSignature  { numerator : n, denominator_power : d, _ } => {
+            if d < 16 {
+                format!("TimeSignature: {}/{}", n, 1u << d)
+            } else {
+                format!("TimeSignature: {}/2^{}", n, d)
+            }
+        }
+        KeySignature   { sharps : s, minor : m } => {
+            format!("KeySignature: {} sharps, {}", s, if m { "minor" } else { "major" })
+        }
+        SequencerSpecific { data : ref d } => { format!("SequencerSpecific: {} bytes", d.len()) }
+        UnknownMeta    { meta_type : t, data : ref d } => { format!("UnknownMeta -- type: {}, {} bytes", t, d.len()) }
     }
 }
 
@@ -458,31 +1136,85 @@ fn message_to_string(m : MidiMessage) -> ~str {
 // In C, I'd memcpy two uint8 bytes into a pointer to a uint16, but give there's no
 // memcpy here (well, without `unsafe`) I'm using silly bit tricks to do number conversions.
 // Got these from how Rust io::net parses IP addresses.
-fn u16_from_u8_at(buf : &[u8], offset : u32) -> u16 {
-   (buf[offset] as u16 << 8) | (buf[offset + 1] as u16)
+//
+// Every read from the file goes through these, so each one checks it's still inside the buffer and
+// hands back a Truncated error if it isn't. Files come from anywhere; they shouldn't crash us.
+fn u16_from_u8_at(buf : &[u8], offset : u32) -> Result<u16, MidiError> {
+   let bytes = try_parse!(slice_at(buf, offset, 2));
+   Ok((bytes[0] as u16 << 8) | (bytes[1] as u16))
+}
+
+fn u32_from_u8_at(buf : &[u8], offset : u32) -> Result<u32, MidiError> {
+   let bytes = try_parse!(slice_at(buf, offset, 4));
+   Ok((bytes[0] as u32 << 24)
+      | (bytes[1] as u32 << 16)
+      | (bytes[2] as u32 << 8)
+      | (bytes[3] as u32))
+}
+
+fn byte_at(buf : &[u8], offset : u32) -> Result<u8, MidiError> {
+    if (offset as uint) < buf.len() {
+        Ok(buf[offset])
+    } else {
+        parse_error(Truncated, offset)
+    }
+}
+
+/// A data byte of a channel or system message, with the top bit masked off.
+fn data_byte_at(buf : &[u8], offset : u32) -> Result<u8, MidiError> {
+    let byte = try_parse!(byte_at(buf, offset));
+    Ok(lower_seven_bits(byte))
+}
+
+/// Where something `length` bytes long starting at `offset` ends, as long as that's inside the
+/// buffer. Written so that a huge length can't overflow its way back into range.
+fn checked_end(buf : &[u8], offset : u32, length : u32) -> Result<u32, MidiError> {
+    let start = offset as uint;
+    if start > buf.len() || (length as uint) > buf.len() - start {
+        parse_error(Truncated, offset)
+    } else {
+        Ok(offset + length)
+    }
 }
 
-fn u32_from_u8_at(buf : &[u8], offset : u32) -> u32 {
-   (buf[offset] as u32 << 24)
-   | (buf[offset + 1] as u32 << 16)
-   | (buf[offset + 2] as u32 << 8)
-   | (buf[offset + 3] as u32)
+fn slice_at<'a>(buf : &'a [u8], offset : u32, length : u32) -> Result<&'a [u8], MidiError> {
+    let end = try_parse!(checked_end(buf, offset, length));
+    Ok(buf.slice(offset as uint, end as uint))
 }
 
-fn get_track_size(buf : &[u8], offset : u32) -> u32 {
-    let size = u32_from_u8_at(buf, offset + 4);
-    size
+fn get_track_size(buf : &[u8], offset : u32) -> Result<u32, MidiError> {
+    u32_from_u8_at(buf, offset + 4)
 }
 
 fn file_format_from_u16(value : u16) -> Option<FileFormat> {
     match value {
-        1 => Some(SingleTrack),
-        2 => Some(MultipleSynchronous),
-        3 => Some(MultipleAsynchronous),
+        0 => Some(SingleTrack),
+        1 => Some(MultipleSynchronous),
+        2 => Some(MultipleAsynchronous),
         _ => None
     }
 }
 
+/// With the top bit clear, the division is ticks per quarter note. With it set, the high byte is
+/// minus the SMPTE frame rate and the low byte is ticks per frame. A zero anywhere would mean
+/// ticks that never end, so those are rejected too.
+fn division_from_u16(value : u16) -> Option<Division> {
+    if value & 0x8000 == 0 {
+        match value {
+            0 => None,
+            tpq => Some(TicksPerQuarter(tpq))
+        }
+    } else {
+        let fps = (-(((value >> 8) as u8) as i8)) as u8;
+        let tpf = (value & 0xFF) as u8;
+        match (fps, tpf) {
+            (_, 0) => None,
+            (24, _) | (25, _) | (29, _) | (30, _) => Some(Smpte{ frames_per_second : fps, ticks_per_frame : tpf }),
+            _ => None
+        }
+    }
+}
+
 fn msb_is_one(number : u8) -> bool {
     number > 127
 }
@@ -490,16 +1222,20 @@ fn lower_seven_bits(number : u8) -> u8 {
     number & 0b01111111
 }
 
-fn is_invalid_status_byte(byte : u8) -> bool {
-    match byte {
-        0 .. 0x7F | 0xF4 | 0xF5 | 0xF7 | 0xF9 => true,
-        _ => false
-    }
+/// Channel messages (0x80 to 0xEF) are the only ones running status can repeat.
+fn is_channel_status(byte : u8) -> bool {
+    byte >= 0x80 && byte < 0xF0
+}
+
+/// Realtime messages (0xF8 to 0xFF) can turn up anywhere, even between the bytes of other
+/// messages, so they don't disturb running status.
+fn is_realtime_status(byte : u8) -> bool {
+    byte >= 0xF8
 }
 
 
-fn get_status_byte(message : MidiMessage) -> u8 {
-    match message {
+fn get_status_byte(message : &MidiMessage) -> u8 {
+    match *message {
         NoteOff         { channel : c, _ } => { 0x80 | c }
         NoteOn          { channel : c, _ } => { 0x90 | c }
         Aftertouch      { channel : c, _ } => { 0xA0 | c }
@@ -509,6 +1245,8 @@ fn get_status_byte(message : MidiMessage) -> u8 {
         PitchWheel      { channel : c, _ } => { 0xE0 | c }
 
         SystemExclusive     {_} => { 0xF0 }
+        SysExContinuation   {_} => { 0xF7 }
+        SysExEscape         {_} => { 0xF7 }
         MidiTimeCode        {_} => { 0xF1 }
         SongPositionPointer {_} => { 0xF2 }
         SongSelect          {_} => { 0xF3 }
@@ -519,92 +1257,406 @@ fn get_status_byte(message : MidiMessage) -> u8 {
         MidiStop                => { 0xFC }
         ActiveSense             => { 0xFE }
         Reset                   => { 0xFF }
-        // InvalidStatus gets an invalid Midi Message, but only for completeness.
-        // Should never happen.
-        _ => { 0xFD }
+        Meta(_)                 => { 0xFF }
     }
 }
 
 
 // Writing
-// Undefined for now, since we just want to read.
-
-
+/// Writes `file` out to `filename` as a standard MIDI file. See `to_bytes`.
+pub fn write_file(filename : &str, file : &MidiFile, style : WriteStyle) -> Result<(), MidiError> {
+    let bytes = try_parse!(to_bytes(file, style));
+    let path = &Path::new(filename);
+    let mut failure = None;
 
-// Tests!
-// Note that for tests that test external-facing definitions (mostly, parse_file), we should be
-// writing that in a separate file called `test.rs` that imports these definitions. The following
-// are just tests for the internal functions -- parse_ticks, parse_tracks, etc.
+    do io_error::cond.trap(|e| {
+        failure = Some(e.to_str());
+    }).inside {
+        File::create(path).write(bytes);
+    }
+    match failure {
+        Some(desc) => Err(MidiError{ kind : WriteFailed(desc), offset : 0, track : None }),
+        None => Ok(())
+    }
+}
 
-#[test]
-fn test_parse_header_standard() {
-   let test1 = [0x4D, 0x54, 0x68, 0x64, 0x00, 0x00, 0x00, 0x06,
-                0x00, 0x01,
-                0x00, 0x05,
-                0x00, 0xa0];
-   let rslt = parse_header(test1);
-   match rslt {
-       None => { assert!(false) }
-       Some(x) => {
-            assert!(x.num_tracks == 5);
-            assert!(x.ticks_per_quarter == 160);
-            match x.file_format {
-                SingleTrack => assert!(true),
-                _ => assert!(false)
-            }
-       }
-   }
+/// Turns a MidiFile into the bytes of a standard MIDI file: the `MThd` chunk, then an `MTrk` chunk
+/// per track, with any unknown chunks put back after the same tracks they followed. Chunk lengths
+/// are worked out from what's there, so `track_length` doesn't have to be kept up to date, and
+/// neither does `num_tracks` unless the style is Lossless. Events are written as they are; a
+/// track missing its EndOfTrack stays that way. More than 65535 tracks won't fit in the header, so
+/// that's an error.
+///
+/// See WriteStyle for the choices. RunningStatus is how most sequencers write files.
+pub fn to_bytes(file : &MidiFile, style : WriteStyle) -> Result<~[u8], MidiError> {
+    if file.tracks.len() > (u16::max_value as uint) {
+        // Where the track count goes in the header.
+        return Err(MidiError{ kind : TooManyTracks(file.tracks.len()), offset : 10, track : None });
+    }
+    let num_tracks = match style {
+        // The header might have been wrong to begin with, and that's part of the file too.
+        Lossless => file.header.num_tracks,
+        _ => file.tracks.len() as u16
+    };
+    let mut out = ~[];
+    write_header(&file.header, num_tracks, &mut out);
+    write_unknown_chunks(file.unknown_chunks, 0, file.tracks.is_empty(), &mut out);
+    for (i, track) in file.tracks.iter().enumerate() {
+        match write_track(track, style, &mut out) {
+            Ok(()) => {}
+            Err(e) => { return Err(MidiError{ track : Some(i), ..e }); }
+        }
+        write_unknown_chunks(file.unknown_chunks, i + 1, i + 1 == file.tracks.len(), &mut out);
+    }
+    if style == Lossless {
+        out.push_all(file.trailing_bytes);
+    }
+    Ok(out)
+}
 
-   let test2  = [0x4D, 0x54, 0x68, 0x64, 0x00, 0x00, 0x00, 0x06,
-                 0x00, 0x02,
-                 0x0a, 0x00,
-                 0x01, 0x00];
-   let rslt2 = parse_header(test2);
-   match rslt2 {
-       None => { assert!(false) }
-       Some(x) => {
-            assert!(x.num_tracks == 2560);
-            assert!(x.ticks_per_quarter == 256);
-            match x.file_format {
-                MultipleSynchronous => assert!(true),
-                _ => assert!(false)
-            }
-       }
-   }
+fn write_header(header : &MidiHeader, num_tracks : u16, out : &mut ~[u8]) {
+    let mut data = ~[];
+    push_u16(header.file_format as u16, &mut data);
+    push_u16(num_tracks, &mut data);
+    push_u16(division_to_u16(header.division), &mut data);
+    data.push_all(header.extra);
+    push_chunk(bytes!("MThd"), data, out);
 }
 
-#[test]
-fn test_parse_header_fail() {
-    let test3  = [0x4D, 0x34, 0x68, 0x64, 0x00, 0x00, 0x00, 0x06,
-                 0x00, 0x02,
-                 0x0a, 0x00,
-                 0x01, 0x00];
-   let rslt3 = parse_header(test3);
-   match rslt3 {
-       None => { assert!(true) }
-       Some(_) => { assert!(false) }
-   }
+/// Writes the unknown chunks that came after `after_track` tracks. Any that claim to come after
+/// more tracks than there are go at the very end, with the `last` lot.
+fn write_unknown_chunks(chunks : &[UnknownChunk], after_track : uint, last : bool, out : &mut ~[u8]) {
+    for chunk in chunks.iter() {
+        if chunk.after_track == after_track || (last && chunk.after_track > after_track) {
+            push_chunk(chunk.id, chunk.data, out);
+        }
+    }
+}
+
+fn write_track(track : &MidiTrack, style : WriteStyle, out : &mut ~[u8]) -> Result<(), MidiError> {
+    // Errors point at where the bad bytes would have gone in the output.
+    let data_offset = (out.len() as u32) + CHUNK_HEADER_LENGTH;
+    let mut data = ~[];
+    let mut last_status = 0x00;
+    for event in track.events.iter() {
+        last_status = try_parse!(write_event(event, last_status, style, &mut data, data_offset));
+    }
+    push_chunk(bytes!("MTrk"), data, out);
+    Ok(())
+}
+
+/// Writes one event, and hands back the running status a reader will have once it's read it.
+fn write_event(event : &MidiEvent, last_status : u8, style : WriteStyle,
+               out : &mut ~[u8], base_offset : u32) -> Result<u8, MidiError> {
+    let plain = EventEncoding{ delta_padding : 0, omit_status : false, length_padding : 0 };
+    let encoding = match (style, event.encoding) {
+        (Lossless, Some(e)) => e,
+        (RunningStatus, _) => EventEncoding{ omit_status : true, ..plain },
+        _ => plain
+    };
+    try_parse!(push_vlq(event.delta_time, encoding.delta_padding, out, base_offset));
+    write_message(&event.message, last_status, encoding, out, base_offset)
+}
+
+/// Writes one message. The status byte is only left out if `encoding` asks for that and running
+/// status allows it, so an edited event can't end up meaning something else.
+fn write_message(message : &MidiMessage, last_status : u8, encoding : EventEncoding,
+                 out : &mut ~[u8], base_offset : u32) -> Result<u8, MidiError> {
+    let length_padding = encoding.length_padding;
+    match *message {
+        Meta(ref meta) => {
+            let (meta_type, data) = meta_to_data(meta);
+            out.push(0xFF);
+            out.push(meta_type);
+            try_parse!(push_vlq(data.len() as u32, length_padding, out, base_offset));
+            out.push_all(data);
+        }
+        SystemExclusive { manufacturer : m, data : ref d, complete : c } => {
+            let mut packet = match m {
+                ShortId(a) => ~[a],
+                ExtendedId(a, b) => ~[0x00, a, b]
+            };
+            packet.push_all(*d);
+            if c { packet.push(0xF7); }
+            try_parse!(push_packet(0xF0, packet, length_padding, out, base_offset));
+        }
+        SysExContinuation { data : ref d, complete : c } => {
+            let mut packet = d.clone();
+            if c { packet.push(0xF7); }
+            try_parse!(push_packet(0xF7, packet, length_padding, out, base_offset));
+        }
+        SysExEscape { data : ref d } => {
+            try_parse!(push_packet(0xF7, *d, length_padding, out, base_offset));
+        }
+        // In a file 0xFF starts a meta event, so a Reset can only go out inside an escape. Readers
+        // take that as a SysEx, which cancels running status.
+        Reset => {
+            try_parse!(push_packet(0xF7, [0xFF], length_padding, out, base_offset));
+            return Ok(0x00);
+        }
+        _ => {
+            let status = get_status_byte(message);
+            if !encoding.omit_status || status != last_status {
+                out.push(status);
+            }
+            for &byte in message_data(message).iter() {
+                // The parser drops the top bit of data bytes, so the writer does too.
+                out.push(lower_seven_bits(byte));
+            }
+        }
+    }
+    Ok(running_status_after(message, last_status))
+}
+
+/// The data bytes that follow the status byte of a channel or system common message.
+fn message_data(message : &MidiMessage) -> ~[u8] {
+    match *message {
+        NoteOff             { key : k, velocity : v, _ } => ~[k, v],
+        NoteOn              { key : k, velocity : v, _ } => ~[k, v],
+        Aftertouch          { key : k, velocity : v, _ } => ~[k, v],
+        ControlChange       { controller : c, value : v, _ } => ~[c, v],
+        ProgramChange       { new_program : p, _ } => ~[p],
+        ChannelPressure     { value : v, _ } => ~[v],
+        PitchWheel          { lsb : l, msb : m, _ } => ~[l, m],
+        MidiTimeCode        { message_type : t, values : v } => ~[t, v],
+        SongPositionPointer { lsb : l, msb : m } => ~[l, m],
+        SongSelect          { song : s } => ~[s],
+        _ => ~[]
+    }
+}
+
+/// The other way around from meta_from_data: a meta event's type byte and data.
+fn meta_to_data(meta : &MetaEvent) -> (u8, ~[u8]) {
+    match *meta {
+        SequenceNumber { number : n } => (0x00, ~[(n >> 8) as u8, n as u8]),
+        Text { text : ref t } => (0x01, t.clone()),
+        Copyright { text : ref t } => (0x02, t.clone()),
+        TrackName { text : ref t } => (0x03, t.clone()),
+        InstrumentName { text : ref t } => (0x04, t.clone()),
+        Lyric { text : ref t } => (0x05, t.clone()),
+        Marker { text : ref t } => (0x06, t.clone()),
+        CuePoint { text : ref t } => (0x07, t.clone()),
+        ProgramName { text : ref t } => (0x08, t.clone()),
+        DeviceName { text : ref t } => (0x09, t.clone()),
+        ChannelPrefix { channel : c } => (0x20, ~[c]),
+        PortPrefix { port : p } => (0x21, ~[p]),
+        EndOfTrack => (0x2F, ~[]),
+        SetTempo { tempo : t } => (0x51, ~[(t >> 16) as u8, (t >> 8) as u8, t as u8]),
+        SmpteOffset { frame_rate : r, hours : h, minutes : m, seconds : s, frames : f, subframes : sf } => {
+            (0x54, ~[((r & 0x03) << 5) | (h & 0x1F), m, s, f, sf])
+        }
+        TimeSignature { numerator : n, denominator_power : d, clocks_per_click : c,
+                        thirty_seconds_per_quarter : t } => (0x58, ~[n, d, c, t]),
+        KeySignature { sharps : s, minor : m } => (0x59, ~[s as u8, if m { 1 } else { 0 }]),
+        SequencerSpecific { data : ref d } => (0x7F, d.clone()),
+        UnknownMeta { meta_type : t, data : ref d } => (t, d.clone())
+    }
+}
+
+/// The other way around from division_from_u16.
+fn division_to_u16(division : Division) -> u16 {
+    match division {
+        TicksPerQuarter(tpq) => tpq & 0x7FFF,
+        Smpte { frames_per_second : fps, ticks_per_frame : tpf } => {
+            (((-(fps as i8)) as u8 as u16) << 8) | (tpf as u16)
+        }
+    }
+}
+
+/// Writes a SysEx packet: its F0 or F7, the length, and then the bytes.
+fn push_packet(status : u8, packet : &[u8], length_padding : u8,
+               out : &mut ~[u8], base_offset : u32) -> Result<(), MidiError> {
+    out.push(status);
+    try_parse!(push_vlq(packet.len() as u32, length_padding, out, base_offset));
+    out.push_all(packet);
+    Ok(())
+}
+
+/// Writes a whole chunk: ID, length and data.
+fn push_chunk(id : &[u8], data : &[u8], out : &mut ~[u8]) {
+    out.push_all(id);
+    push_u32(data.len() as u32, out);
+    out.push_all(data);
+}
+
+/// Writes a variable-length quantity, padded out in front with `padding` zero groups if that still
+/// fits in four bytes. Anything over MAX_VLQ can't be written at all.
+fn push_vlq(value : u32, padding : u8, out : &mut ~[u8], base_offset : u32) -> Result<(), MidiError> {
+    let mut bytes = [0u8, ..4];
+    match write_vlq(value, &mut bytes) {
+        0 => parse_error(VarLenTooLong, base_offset + (out.len() as u32)),
+        length => {
+            if length + (padding as uint) <= 4 {
+                for _ in range(0, padding) {
+                    out.push(0x80);
+                }
+            }
+            out.push_all(bytes.slice_to(length));
+            Ok(())
+        }
+    }
+}
+
+fn push_u16(value : u16, out : &mut ~[u8]) {
+    out.push((value >> 8) as u8);
+    out.push(value as u8);
+}
+
+fn push_u32(value : u32, out : &mut ~[u8]) {
+    out.push((value >> 24) as u8);
+    out.push((value >> 16) as u8);
+    out.push((value >> 8) as u8);
+    out.push(value as u8);
+}
+
+
+
+// Tests!
+// Note that for tests that test external-facing definitions (mostly, parse_file), we should be
+// writing that in a separate file called `test.rs` that imports these definitions. The following
+// are just tests for the internal functions -- read_vlq, parse_tracks, etc.
+
+#[test]
+fn test_parse_header_standard() {
+   let test1 = [0x4D, 0x54, 0x68, 0x64, 0x00, 0x00, 0x00, 0x06,
+                0x00, 0x01,
+                0x00, 0x05,
+                0x00, 0xa0];
+   let rslt = parse_header(test1);
+   match rslt {
+       Err(_) => { assert!(false) }
+       Ok(x) => {
+            assert!(x.num_tracks == 5);
+            assert!(x.division == TicksPerQuarter(160));
+            match x.file_format {
+                MultipleSynchronous => assert!(true),
+                _ => assert!(false)
+            }
+       }
+   }
+
+   let test2  = [0x4D, 0x54, 0x68, 0x64, 0x00, 0x00, 0x00, 0x06,
+                 0x00, 0x02,
+                 0x0a, 0x00,
+                 0x01, 0x00];
+   let rslt2 = parse_header(test2);
+   match rslt2 {
+       Err(_) => { assert!(false) }
+       Ok(x) => {
+            assert!(x.num_tracks == 2560);
+            assert!(x.division == TicksPerQuarter(256));
+            match x.file_format {
+                MultipleAsynchronous => assert!(true),
+                _ => assert!(false)
+            }
+       }
+   }
+}
+
+#[test]
+fn test_parse_header_format_zero() {
+   let test1 = [0x4D, 0x54, 0x68, 0x64, 0x00, 0x00, 0x00, 0x06,
+                0x00, 0x00,
+                0x00, 0x01,
+                0x01, 0xe0];
+   match parse_header(test1) {
+       Err(_) => { assert!(false) }
+       Ok(x) => {
+            assert!(x.num_tracks == 1);
+            assert!(x.division == TicksPerQuarter(480));
+            match x.file_format {
+                SingleTrack => assert!(true),
+                _ => assert!(false)
+            }
+       }
+   }
+}
+
+#[test]
+fn test_parse_header_smpte() {
+   // -25 frames per second, 40 ticks per frame: millisecond resolution.
+   let test1 = [0x4D, 0x54, 0x68, 0x64, 0x00, 0x00, 0x00, 0x06,
+                0x00, 0x00,
+                0x00, 0x01,
+                0xE7, 0x28];
+   match parse_header(test1) {
+       Err(_) => { assert!(false) }
+       Ok(x) => {
+            assert!(x.division == Smpte{ frames_per_second : 25, ticks_per_frame : 40 });
+            assert!(x.division.ticks_to_micros(1000, 500000) == 1000000);
+       }
+   }
+
+   // -23 isn't a frame rate.
+   let test2 = [0x4D, 0x54, 0x68, 0x64, 0x00, 0x00, 0x00, 0x06,
+                0x00, 0x00,
+                0x00, 0x01,
+                0xE9, 0x28];
+   assert!(parse_header(test2).is_err());
+
+   // Zero ticks per quarter note.
+   let test3 = [0x4D, 0x54, 0x68, 0x64, 0x00, 0x00, 0x00, 0x06,
+                0x00, 0x00,
+                0x00, 0x01,
+                0x00, 0x00];
+   assert!(parse_header(test3).is_err());
+}
+
+#[test]
+fn test_division_ticks_to_micros() {
+    assert!(TicksPerQuarter(96).ticks_to_micros(96, 500000) == 500000);
+    assert!(TicksPerQuarter(96).ticks_to_micros(48, 600000) == 300000);
+    assert!(Smpte{ frames_per_second : 24, ticks_per_frame : 4 }.ticks_to_micros(96, 500000) == 1000000);
+    assert!(Smpte{ frames_per_second : 30, ticks_per_frame : 80 }.ticks_to_micros(2400, 1) == 1000000);
+    // 30 drop-frame frames last 1.001 seconds.
+    assert!(Smpte{ frames_per_second : 29, ticks_per_frame : 1 }.ticks_to_micros(30, 500000) == 1001000);
+}
+
+#[test]
+fn test_file_format_numbering() {
+    match file_format_from_u16(0) { Some(SingleTrack) => {} _ => { assert!(false) } }
+    match file_format_from_u16(1) { Some(MultipleSynchronous) => {} _ => { assert!(false) } }
+    match file_format_from_u16(2) { Some(MultipleAsynchronous) => {} _ => { assert!(false) } }
+    assert!(file_format_from_u16(3).is_none());
+
+    assert!(file_format_to_string(MultipleSynchronous) == ~"Multiple track, synchronous");
+    assert!(file_format_to_string(MultipleAsynchronous) == ~"Multiple track, asynchronous");
+}
+
+#[test]
+fn test_parse_header_fail() {
+    let test3  = [0x4D, 0x34, 0x68, 0x64, 0x00, 0x00, 0x00, 0x06,
+                 0x00, 0x02,
+                 0x0a, 0x00,
+                 0x01, 0x00];
+   let rslt3 = parse_header(test3);
+   match rslt3 {
+       Err(_) => { assert!(true) }
+       Ok(_) => { assert!(false) }
+   }
 }
 
 #[test]
 fn test_parse_ticks_easy() {
     let test_buf = [0x50, 0x90, 0x26, 0x3C];
-    match parse_ticks(test_buf, 0) {
-        (ticks, new_offset) => {
+    match read_vlq(test_buf, 0) {
+        Ok((ticks, new_offset)) => {
             assert!(ticks == 80);
             assert!(new_offset == 1);
         }
+        Err(_) => { assert!(false); }
     }
 }
 
 #[test]
 fn test_parse_ticks_hard() {
     let test_buf = [0x83, 0x60, 0x26, 0x00];
-    match parse_ticks(test_buf, 0) {
-        (ticks, new_offset) => {
+    match read_vlq(test_buf, 0) {
+        Ok((ticks, new_offset)) => {
             assert!(ticks == 480);
             assert!(new_offset == 2);
         }
+        Err(_) => { assert!(false); }
     }
 }
 
@@ -612,7 +1664,7 @@ fn test_parse_ticks_hard() {
 fn test_parse_event_one() {
     let test_buf = [0x88, 0x05, 0x03];
     match parse_message(test_buf, 0, 0x80) {
-        Some((NoteOff{channel : c, key : k, velocity : v}, 3)) => {
+        Ok((NoteOff{channel : c, key : k, velocity : v}, 3)) => {
             assert!(c == 8);
             assert!(k == 5);
             assert!(v == 3);
@@ -625,7 +1677,7 @@ fn test_parse_event_one() {
 fn test_parse_event_two() {
     let test_buf = [0xA3, 0x04, 0x09];
     match parse_message(test_buf, 0, 0x80) {
-        Some((Aftertouch{channel : c, key : k, velocity : v}, 3)) => {
+        Ok((Aftertouch{channel : c, key : k, velocity : v}, 3)) => {
             assert!(c == 3);
             assert!(k == 4);
             assert!(v == 9);
@@ -655,8 +1707,8 @@ fn test_parse_track_all_complete() {
         0x82, 0x05, 0x04        // NoteOff, channel 2, key 5, velocity 4
         ];
 
-    match parse_track(test_buf, 0) {
-        Some(track) => {
+    match parse_track(test_buf, 0, false) {
+        Ok(track) => {
             assert!(track.track_length == 17);
             
             assert!(track.events[0].delta_time == 80);
@@ -724,8 +1776,8 @@ fn test_parse_track_some_ommitted() {
         0x13, 0x05              // Omit status (Aftertouch), channel 2, key 19, velocity 5
         ];
 
-    match parse_track(test_buf, 0) {
-        Some(track) => {
+    match parse_track(test_buf, 0, false) {
+        Ok(track) => {
             assert!(track.track_length == 15);
 
             assert!(track.events[0].delta_time == 80);
@@ -771,3 +1823,714 @@ fn test_parse_track_some_ommitted() {
         _ => { assert!(false); }
     }
 }
+
+#[test]
+fn test_parse_meta_tempo() {
+    let test_buf = [0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20];
+    match parse_message(test_buf, 0, 0x90) {
+        Ok((Meta(SetTempo{ tempo : t }), 6)) => { assert!(t == 500000); }
+        _ => { assert!(false); }
+    }
+}
+
+#[test]
+fn test_parse_meta_typed() {
+    let time_sig = [0xFF, 0x58, 0x04, 0x06, 0x03, 0x18, 0x08];
+    match parse_message(time_sig, 0, 0x00) {
+        Ok((Meta(TimeSignature{ numerator : n, denominator_power : d, clocks_per_click : c,
+                                  thirty_seconds_per_quarter : t }), 7)) => {
+            assert!(n == 6);
+            assert!(d == 3);
+            assert!(c == 24);
+            assert!(t == 8);
+        }
+        _ => { assert!(false); }
+    }
+
+    let key_sig = [0xFF, 0x59, 0x02, 0xFD, 0x01];
+    match parse_message(key_sig, 0, 0x00) {
+        Ok((Meta(KeySignature{ sharps : s, minor : m }), 5)) => {
+            assert!(s == -3);
+            assert!(m);
+        }
+        _ => { assert!(false); }
+    }
+
+    let name = [0xFF, 0x03, 0x05, 0x50, 0x69, 0x61, 0x6E, 0x6F];
+    match parse_message(name, 0, 0x00) {
+        Ok((Meta(TrackName{ text : t }), 8)) => { assert!(text_to_str(t) == ~"Piano"); }
+        _ => { assert!(false); }
+    }
+
+    let end = [0xFF, 0x2F, 0x00];
+    match parse_message(end, 0, 0x00) {
+        Ok((Meta(EndOfTrack), 3)) => { assert!(true); }
+        _ => { assert!(false); }
+    }
+}
+
+#[test]
+fn test_parse_meta_unknown() {
+    // An unassigned meta type, and a tempo with the wrong length: both are kept as raw bytes.
+    let unknown = [0xFF, 0x60, 0x02, 0x01, 0x02];
+    match parse_message(unknown, 0, 0x00) {
+        Ok((Meta(UnknownMeta{ meta_type : t, data : d }), 5)) => {
+            assert!(t == 0x60);
+            assert!(d == ~[0x01, 0x02]);
+        }
+        _ => { assert!(false); }
+    }
+
+    let short_tempo = [0xFF, 0x51, 0x02, 0x07, 0xA1];
+    match parse_message(short_tempo, 0, 0x00) {
+        Ok((Meta(UnknownMeta{ meta_type : t, _ }), 5)) => { assert!(t == 0x51); }
+        _ => { assert!(false); }
+    }
+
+    let truncated = [0xFF, 0x03, 0x05, 0x50, 0x69];
+    assert!(parse_message(truncated, 0, 0x00).is_err());
+}
+
+#[test]
+fn test_parse_track_with_meta() {
+    // A meta event in between NoteOns cancels running status, so the second NoteOn has to say
+    // what it is again.
+    let test_buf = [('M' as u8), ('T' as u8), ('r' as u8), ('k' as u8),
+
+        0x00, 0x00, 0x00, 0x1B, // Track length: 27
+
+        0x00,                   // Delta time: 0
+        0xFF, 0x03, 0x04,       // TrackName, 4 bytes
+        0x4C, 0x65, 0x61, 0x64, // "Lead"
+
+        0x00,                   // Delta time: 0
+        0x91, 0x3C, 0x40,       // NoteOn, channel 1, key 60, velocity 64
+
+        0x10,                   // Delta time: 16
+        0xFF, 0x51, 0x03,       // SetTempo
+        0x07, 0xA1, 0x20,       // 500000
+
+        0x20,                   // Delta time: 32
+        0x91, 0x3C, 0x00,       // NoteOn, channel 1, key 60, velocity 0
+
+        0x00,                   // Delta time: 0
+        0xFF, 0x2F, 0x00        // EndOfTrack
+        ];
+
+    match parse_track(test_buf, 0, false) {
+        Ok(track) => {
+            assert!(track.events.len() == 5);
+            assert!(track.name() == Some(~"Lead"));
+            match track.events[3].message {
+                NoteOn{ channel : c, key : k, velocity : v } => {
+                    assert!(c == 1);
+                    assert!(k == 60);
+                    assert!(v == 0);
+                }
+                _ => { assert!(false) }
+            }
+            assert!(track.events[4].message == Meta(EndOfTrack));
+        }
+        Err(_) => { assert!(false); }
+    }
+
+    // Leaving the status byte out after the SetTempo is an error.
+    let mut no_status = test_buf.to_owned();
+    no_status.remove(28);
+    no_status[7] = 0x1A;
+    match parse_track(no_status, 0, false) {
+        Err(e) => {
+            assert!(e.kind == NoRunningStatus);
+            assert!(e.offset == 28);
+        }
+        Ok(_) => { assert!(false); }
+    }
+}
+
+#[test]
+fn test_parse_sysex() {
+    // GM System On, a universal non-realtime message.
+    let gm_reset = [0xF0, 0x05, 0x7E, 0x7F, 0x09, 0x01, 0xF7];
+    match parse_message(gm_reset, 0, 0x00) {
+        Ok((SystemExclusive{ manufacturer : ShortId(m), data : d, complete : c }, 7)) => {
+            assert!(m == 0x7E);
+            assert!(d == ~[0x7F, 0x09, 0x01]);
+            assert!(c);
+        }
+        _ => { assert!(false); }
+    }
+
+    // A three-byte manufacturer ID.
+    let extended = [0xF0, 0x05, 0x00, 0x20, 0x29, 0x01, 0xF7];
+    match parse_message(extended, 0, 0x00) {
+        Ok((SystemExclusive{ manufacturer : ExtendedId(a, b), data : d, complete : c }, 7)) => {
+            assert!(a == 0x20);
+            assert!(b == 0x29);
+            assert!(d == ~[0x01]);
+            assert!(c);
+        }
+        _ => { assert!(false); }
+    }
+
+    let escape = [0xF7, 0x01, 0xFA];
+    match parse_message(escape, 0, 0x00) {
+        Ok((SysExEscape{ data : d }, 3)) => { assert!(d == ~[0xFA]); }
+        _ => { assert!(false); }
+    }
+
+    let truncated = [0xF0, 0x0A, 0x41, 0x10];
+    assert!(parse_message(truncated, 0, 0x00).is_err());
+}
+
+#[test]
+fn test_parse_track_sysex_packets() {
+    // A Roland GS reset split over two packets, an escaped Start, then a NoteOn. SysEx cancels
+    // running status, so the NoteOn restates its status byte.
+    let test_buf = [('M' as u8), ('T' as u8), ('r' as u8), ('k' as u8),
+
+        0x00, 0x00, 0x00, 0x1C, // Track length: 28
+
+        0x00,                   // Delta time: 0
+        0x90, 0x3C, 0x40,       // NoteOn, channel 0, key 60, velocity 64
+
+        0x00,                   // Delta time: 0
+        0xF0, 0x05,             // SysEx, 5 bytes, no F7 so it continues
+        0x41, 0x10, 0x42, 0x12, 0x40,
+
+        0x10,                   // Delta time: 16
+        0xF7, 0x05,             // Continuation, 5 bytes, ending in F7
+        0x00, 0x7F, 0x00, 0x41, 0xF7,
+
+        0x00,                   // Delta time: 0
+        0xF7, 0x01, 0xFA,       // Escape: MIDI Start
+
+        0x00,                   // Delta time: 0
+        0x90, 0x3C, 0x00        // NoteOn, channel 0, key 60, velocity 0
+        ];
+
+    match parse_track(test_buf, 0, false) {
+        Ok(track) => {
+            assert!(track.events.len() == 5);
+            match track.events[1].message {
+                SystemExclusive{ manufacturer : ShortId(m), data : ref d, complete : c } => {
+                    assert!(m == 0x41);
+                    assert!(*d == ~[0x10, 0x42, 0x12, 0x40]);
+                    assert!(!c);
+                }
+                _ => { assert!(false) }
+            }
+            match track.events[2].message {
+                SysExContinuation{ data : ref d, complete : c } => {
+                    assert!(*d == ~[0x00, 0x7F, 0x00, 0x41]);
+                    assert!(c);
+                }
+                _ => { assert!(false) }
+            }
+            assert!(track.events[3].message == SysExEscape{ data : ~[0xFA] });
+            assert!(track.events[4].message == NoteOn{ channel : 0, key : 60, velocity : 0 });
+        }
+        Err(_) => { assert!(false); }
+    }
+
+    // Leaving the status byte out after the escape is an error.
+    let mut no_status = test_buf.to_owned();
+    no_status.remove(33);
+    no_status[7] = 0x1B;
+    match parse_track(no_status, 0, false) {
+        Err(e) => {
+            assert!(e.kind == NoRunningStatus);
+            assert!(e.offset == 33);
+        }
+        Ok(_) => { assert!(false); }
+    }
+}
+
+#[test]
+fn test_parse_errors() {
+    let bad_format = [0x4D, 0x54, 0x68, 0x64, 0x00, 0x00, 0x00, 0x06,
+                      0x00, 0x05,
+                      0x00, 0x01,
+                      0x00, 0x60];
+    match parse_header(bad_format) {
+        Err(e) => {
+            assert!(e.kind == UnknownFileFormat(5));
+            assert!(e.offset == 8);
+            assert!(e.track.is_none());
+        }
+        Ok(_) => { assert!(false); }
+    }
+
+    let bad_status = [('M' as u8), ('T' as u8), ('r' as u8), ('k' as u8),
+        0x00, 0x00, 0x00, 0x02, // Track length: 2
+        0x00,                   // Delta time: 0
+        0xFD                    // Undefined status
+        ];
+    match parse_track(bad_status, 0, false) {
+        Err(e) => {
+            assert!(e.kind == UnknownStatus(0xFD));
+            assert!(e.offset == 9);
+        }
+        Ok(_) => { assert!(false); }
+    }
+
+    let err = MidiError{ kind : Truncated, offset : 40, track : Some(2) };
+    assert!(err.to_str() == ~"data ends too soon at byte 40, in track 3");
+}
+
+#[test]
+fn test_parse_running_status_rules() {
+    // A realtime message in between leaves running status alone.
+    let clock = [('M' as u8), ('T' as u8), ('r' as u8), ('k' as u8),
+        0x00, 0x00, 0x00, 0x09, // Track length: 9
+        0x00, 0x91, 0x3C, 0x40, // NoteOn, channel 1
+        0x00, 0xF8,             // MidiClock
+        0x00, 0x3E, 0x40        // Omit status (NoteOn)
+        ];
+    match parse_track(clock, 0, false) {
+        Ok(track) => {
+            assert!(track.events[1].message == MidiClock);
+            assert!(track.events[2].message == NoteOn{ channel : 1, key : 0x3E, velocity : 0x40 });
+        }
+        Err(_) => { assert!(false); }
+    }
+
+    // System common messages, meta events and SysEx all cancel it.
+    let cancellers = [~[0xF6], ~[0xFF, 0x01, 0x00], ~[0xF0, 0x02, 0x7E, 0xF7], ~[0xF7, 0x01, 0xF8]];
+    for canceller in cancellers.iter() {
+        let mut buf = ~[('M' as u8), ('T' as u8), ('r' as u8), ('k' as u8),
+                        0x00, 0x00, 0x00, (8 + canceller.len()) as u8,
+                        0x00, 0x91, 0x3C, 0x40,
+                        0x00];
+        buf.push_all(*canceller);
+        buf.push_all([0x00, 0x3E, 0x40]);
+        match parse_track(buf, 0, false) {
+            Err(e) => {
+                assert!(e.kind == NoRunningStatus);
+                assert!(e.offset as uint == 14 + canceller.len());
+            }
+            Ok(_) => { assert!(false); }
+        }
+    }
+
+    // And a track can't start with a data byte, since there's nothing before it to repeat.
+    let no_status = [0x3C, 0x40];
+    match parse_message(no_status, 0, 0x00) {
+        Err(e) => {
+            assert!(e.kind == NoRunningStatus);
+            assert!(e.offset == 0);
+        }
+        Ok(_) => { assert!(false); }
+    }
+}
+
+#[test]
+fn test_parse_file_missing() {
+    match parse_file("midis/does_not_exist.mid") {
+        Err(MidiError{ kind : ReadFailed(_), offset : 0, track : None }) => { assert!(true); }
+        _ => { assert!(false); }
+    }
+}
+
+#[test]
+fn test_parse_truncated_never_panics() {
+    // Every prefix of a good track is a truncated track, and has to come back as an error.
+    let test_buf = [('M' as u8), ('T' as u8), ('r' as u8), ('k' as u8),
+        0x00, 0x00, 0x00, 0x15, // Track length: 21
+        0x00, 0xFF, 0x03, 0x02, 0x48, 0x69,     // TrackName "Hi"
+        0x00, 0xF0, 0x03, 0x7E, 0x09, 0xF7,     // SysEx
+        0x83, 0x60, 0x92, 0x05, 0x04,           // NoteOn after 480 ticks
+        0x00, 0xFF, 0x2F, 0x00                  // EndOfTrack
+        ];
+    assert!(parse_track(test_buf, 0, false).is_ok());
+    for n in range(0, test_buf.len()) {
+        match parse_track(test_buf.slice_to(n), 0, false) {
+            Err(e) => { assert!(e.kind == Truncated); }
+            Ok(_) => { assert!(false); }
+        }
+    }
+
+    let header = [0x4D, 0x54, 0x68, 0x64, 0x00, 0x00, 0x00, 0x06,
+                  0x00, 0x00, 0x00, 0x01, 0x00, 0x60];
+    for n in range(0, header.len()) {
+        assert!(parse_header(header.slice_to(n)).is_err());
+    }
+}
+
+#[test]
+fn test_parse_hostile_lengths() {
+    // A track claiming to be 4GB long.
+    let huge_track = [('M' as u8), ('T' as u8), ('r' as u8), ('k' as u8),
+        0xFF, 0xFF, 0xFF, 0xFF,
+        0x00, 0x90, 0x3C, 0x40];
+    match parse_track(huge_track, 0, false) {
+        Err(e) => {
+            assert!(e.kind == Truncated);
+            assert!(e.offset == 8);
+        }
+        Ok(_) => { assert!(false); }
+    }
+
+    // A meta event whose length runs past the end of its track, into whatever follows.
+    let long_meta = [('M' as u8), ('T' as u8), ('r' as u8), ('k' as u8),
+        0x00, 0x00, 0x00, 0x04,
+        0x00, 0xFF, 0x01, 0x05,
+        0x41, 0x42, 0x43, 0x44, 0x45];
+    match parse_track(long_meta, 0, false) {
+        Err(e) => { assert!(e.kind == Truncated); }
+        Ok(_) => { assert!(false); }
+    }
+
+    // A five-byte delta time.
+    let long_delta = [0x81, 0x81, 0x81, 0x81, 0x01];
+    match read_vlq(long_delta, 0) {
+        Err(e) => { assert!(e.kind == VarLenTooLong); }
+        Ok(_) => { assert!(false); }
+    }
+}
+
+#[test]
+fn test_parse_header_long() {
+    // A header chunk of length 8, with two bytes we don't know what to do with.
+    let test1 = [0x4D, 0x54, 0x68, 0x64, 0x00, 0x00, 0x00, 0x08,
+                 0x00, 0x00,
+                 0x00, 0x01,
+                 0x00, 0x60,
+                 0xAB, 0xCD];
+    match parse_header(test1) {
+        Ok(x) => {
+            assert!(x.division == TicksPerQuarter(96));
+            assert!(x.extra == ~[0xAB, 0xCD]);
+        }
+        Err(_) => { assert!(false) }
+    }
+
+    let short = [0x4D, 0x54, 0x68, 0x64, 0x00, 0x00, 0x00, 0x05,
+                 0x00, 0x00, 0x00, 0x01, 0x00, 0x60];
+    match parse_header(short) {
+        Err(e) => { assert!(e.kind == BadHeaderChunk); }
+        Ok(_) => { assert!(false) }
+    }
+}
+
+#[test]
+fn test_chunks() {
+    let test_buf = [0x4D, 0x54, 0x68, 0x64, 0x00, 0x00, 0x00, 0x01, 0x07,
+                    0x58, 0x59, 0x5A, 0x5A, 0x00, 0x00, 0x00, 0x00,
+                    0x4D, 0x54, 0x72, 0x6B, 0x00, 0x00, 0x00, 0x02, 0x01, 0x02,
+                    0x4D, 0x54, 0x72, 0x6B, 0x00, 0x00, 0x00, 0x09, 0x01];
+    let mut iter = chunks(test_buf);
+    match iter.next() {
+        Some(Ok(c)) => {
+            assert!(c.id == bytes!("MThd"));
+            assert!(c.offset == 0);
+            assert!(c.data == &[0x07]);
+        }
+        _ => { assert!(false); }
+    }
+    match iter.next() {
+        Some(Ok(c)) => {
+            assert!(c.id == bytes!("XYZZ"));
+            assert!(c.data.len() == 0);
+        }
+        _ => { assert!(false); }
+    }
+    match iter.next() {
+        Some(Ok(c)) => {
+            assert!(c.offset == 17);
+            assert!(c.data == &[0x01, 0x02]);
+        }
+        _ => { assert!(false); }
+    }
+    // The last one says it has 9 bytes, but only has 1.
+    match iter.next() {
+        Some(Err(e)) => {
+            assert!(e.kind == Truncated);
+            assert!(e.offset == 35);
+        }
+        _ => { assert!(false); }
+    }
+    assert!(iter.next().is_none());
+}
+
+#[test]
+fn test_read_vlq_lengths() {
+    // The examples from the spec, at each length.
+    let cases = [(~[0x00], 0x00u32), (~[0x40], 0x40), (~[0x7F], 0x7F),
+                 (~[0x81, 0x00], 0x80), (~[0xC0, 0x00], 0x2000), (~[0xFF, 0x7F], 0x3FFF),
+                 (~[0x81, 0x80, 0x00], 0x4000), (~[0xC0, 0x80, 0x00], 0x100000),
+                 (~[0xFF, 0xFF, 0x7F], 0x1FFFFF), (~[0x81, 0x80, 0x80, 0x00], 0x200000),
+                 (~[0xC0, 0x80, 0x80, 0x00], 0x8000000), (~[0xFF, 0xFF, 0xFF, 0x7F], 0xFFFFFFF)];
+    for &(ref bytes, value) in cases.iter() {
+        match read_vlq(*bytes, 0) {
+            Ok((v, end)) => {
+                assert!(v == value);
+                assert!(end as uint == bytes.len());
+            }
+            Err(_) => { assert!(false); }
+        }
+        assert!(encode_vlq(value) == Some(bytes.clone()));
+    }
+}
+
+#[test]
+fn test_read_vlq_rejects_overlong() {
+    let overlong = [[0x80, 0x00], [0x80, 0x7F]];
+    for bytes in overlong.iter() {
+        match read_vlq(*bytes, 0) {
+            Err(e) => { assert!(e.kind == OverlongVarLen); }
+            Ok(_) => { assert!(false); }
+        }
+    }
+    // Zero groups after the first byte are fine: that's just 0x4000.
+    assert!(read_vlq([0x81, 0x80, 0x00], 0).is_ok());
+    assert!(encode_vlq(MAX_VLQ + 1).is_none());
+}
+
+#[test]
+fn test_vlq_round_trip_exhaustive() {
+    // Every number a VLQ can hold, there and back again.
+    let mut bytes = [0u8, ..4];
+    let mut value = 0u32;
+    loop {
+        let length = write_vlq(value, &mut bytes);
+        match read_vlq(bytes.slice_to(length), 0) {
+            Ok((v, end)) => {
+                assert!(v == value);
+                assert!(end as uint == length);
+            }
+            Err(_) => { fail!("Couldn't read back {}", value); }
+        }
+        if value == MAX_VLQ {
+            break;
+        }
+        value += 1;
+    }
+}
+
+#[test]
+fn test_split_channels() {
+    let track = MidiTrack::from_messages(~[(0,  Meta(TrackName{ text : ~[0x41] })),
+                                          (0,  NoteOn{ channel : 9, key : 36, velocity : 100 }),
+                                          (24, NoteOn{ channel : 0, key : 60, velocity : 100 }),
+                                          (24, NoteOn{ channel : 9, key : 36, velocity : 0 }),
+                                          (24, NoteOn{ channel : 0, key : 60, velocity : 0 }),
+                                          (0,  Meta(EndOfTrack))]);
+    assert!(track.channels() == ~[0, 9]);
+    assert!(Meta(EndOfTrack).channel().is_none());
+
+    let split = track.split_channels();
+    assert!(split.map(|&(c, _)| c) == ~[0, 9]);
+    let tracks = split.map(|&(_, ref t)| t.clone());
+    assert!(tracks[0].name() == Some(~"A"));
+    assert!(tracks[0].events.map(|e| e.delta_time) == ~[0, 24, 48, 0]);
+    assert!(tracks[1].events.map(|e| e.delta_time) == ~[0, 0, 48, 24]);
+    assert!(tracks[1].events[1].message == NoteOn{ channel : 9, key : 36, velocity : 100 });
+
+    // Nothing but the meta events is left when no channel is wanted.
+    assert!(track.filter_channels([]).events.len() == 2);
+}
+
+#[test]
+fn test_to_bytes() {
+    let file = MidiFile::from_messages(SingleTrack, TicksPerQuarter(96),
+                                       ~[~[(0,  NoteOn{ channel : 0, key : 0x3C, velocity : 0x40 }),
+                                           (96, NoteOn{ channel : 0, key : 0x3C, velocity : 0x00 }),
+                                           (0,  Meta(EndOfTrack))]]);
+    let header = ~[0x4D, 0x54, 0x68, 0x64, 0x00, 0x00, 0x00, 0x06,
+                   0x00, 0x00, 0x00, 0x01, 0x00, 0x60];
+
+    let mut plain = header.clone();
+    plain.push_all([('M' as u8), ('T' as u8), ('r' as u8), ('k' as u8),
+                    0x00, 0x00, 0x00, 0x0C,
+                    0x00, 0x90, 0x3C, 0x40,
+                    0x60, 0x90, 0x3C, 0x00,
+                    0x00, 0xFF, 0x2F, 0x00]);
+    assert!(to_bytes(&file, Plain) == Ok(plain));
+
+    let mut running = header.clone();
+    running.push_all([('M' as u8), ('T' as u8), ('r' as u8), ('k' as u8),
+                      0x00, 0x00, 0x00, 0x0B,
+                      0x00, 0x90, 0x3C, 0x40,
+                      0x60, 0x3C, 0x00,
+                      0x00, 0xFF, 0x2F, 0x00]);
+    assert!(to_bytes(&file, RunningStatus) == Ok(running.clone()));
+
+    match parse_bytes(running) {
+        Ok(back) => {
+            assert!(back.header.num_tracks == 1);
+            assert!(back.tracks[0].track_length == 11);
+            assert!(back.tracks[0].events[1].delta_time == 96);
+            assert!(back.tracks[0].events[1].message == NoteOn{ channel : 0, key : 0x3C, velocity : 0x00 });
+        }
+        Err(_) => { assert!(false); }
+    }
+}
+
+#[test]
+fn test_to_bytes_running_status_rules() {
+    // The clock leaves running status alone, but the meta event means the status has to be
+    // written again.
+    let file = MidiFile::from_messages(SingleTrack, TicksPerQuarter(96),
+                                       ~[~[(0, NoteOn{ channel : 1, key : 0x3C, velocity : 0x40 }),
+                                           (0, MidiClock),
+                                           (0, NoteOn{ channel : 1, key : 0x3E, velocity : 0x40 }),
+                                           (0, Meta(Text{ text : ~[] })),
+                                           (0, NoteOn{ channel : 1, key : 0x40, velocity : 0x40 }),
+                                           (0, Reset),
+                                           (0, NoteOn{ channel : 1, key : 0x41, velocity : 0x40 })]]);
+    match to_bytes(&file, RunningStatus) {
+        Ok(bytes) => {
+            let track_data = ~[0x00, 0x91, 0x3C, 0x40,
+                               0x00, 0xF8,
+                               0x00, 0x3E, 0x40,
+                               0x00, 0xFF, 0x01, 0x00,
+                               0x00, 0x91, 0x40, 0x40,
+                               0x00, 0xF7, 0x01, 0xFF,
+                               0x00, 0x91, 0x41, 0x40];
+            assert!(bytes.slice_from(22).to_owned() == track_data);
+            assert!(parse_bytes(bytes).is_ok());
+        }
+        Err(_) => { assert!(false); }
+    }
+}
+
+#[test]
+fn test_to_bytes_sysex_and_meta() {
+    let file = MidiFile::from_messages(SingleTrack, TicksPerQuarter(96),
+                                       ~[~[(0, SystemExclusive{ manufacturer : ExtendedId(0x20, 0x29), data : ~[0x01],
+                                                                complete : false }),
+                                           (0, SysExContinuation{ data : ~[0x02], complete : true }),
+                                           (0, Meta(SetTempo{ tempo : 500000 })),
+                                           (0, Meta(KeySignature{ sharps : -2, minor : true })),
+                                           (0, Meta(SmpteOffset{ frame_rate : 3, hours : 1, minutes : 2, seconds : 3,
+                                                                 frames : 4, subframes : 5 }))]]);
+    match to_bytes(&file, RunningStatus) {
+        Ok(bytes) => {
+            let track_data = ~[0x00, 0xF0, 0x04, 0x00, 0x20, 0x29, 0x01,
+                               0x00, 0xF7, 0x02, 0x02, 0xF7,
+                               0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20,
+                               0x00, 0xFF, 0x59, 0x02, 0xFE, 0x01,
+                               0x00, 0xFF, 0x54, 0x05, 0x61, 0x02, 0x03, 0x04, 0x05];
+            assert!(bytes.slice_from(22).to_owned() == track_data);
+            match parse_bytes(bytes) {
+                Ok(back) => {
+                    for i in range(0, 5u) {
+                        assert!(back.tracks[0].events[i].message == file.tracks[0].events[i].message);
+                    }
+                }
+                Err(_) => { assert!(false); }
+            }
+        }
+        Err(_) => { assert!(false); }
+    }
+}
+
+#[test]
+fn test_to_bytes_errors() {
+    // A delta time too big to write, after the 14 byte header and 8 byte chunk header.
+    let file = MidiFile::from_messages(SingleTrack, TicksPerQuarter(96),
+                                       ~[~[(MAX_VLQ + 1, Meta(EndOfTrack))]]);
+    match to_bytes(&file, Plain) {
+        Err(e) => {
+            assert!(e.kind == VarLenTooLong);
+            assert!(e.offset == 22);
+            assert!(e.track == Some(0));
+        }
+        Ok(_) => { assert!(false); }
+    }
+
+    // The header can't count this many tracks.
+    let crowded = MidiFile::from_messages(SingleTrack, TicksPerQuarter(96), std::vec::from_elem(65536, ~[]));
+    match to_bytes(&crowded, Plain) {
+        Err(e) => {
+            assert!(e.kind == TooManyTracks(65536));
+            assert!(e.offset == 10);
+        }
+        Ok(_) => { assert!(false); }
+    }
+
+    let division = Smpte{ frames_per_second : 29, ticks_per_frame : 40 };
+    assert!(division_from_u16(division_to_u16(division)) == Some(division));
+}
+
+#[test]
+fn test_parse_lossless_round_trip() {
+    let test_buf = [0x4D, 0x54, 0x68, 0x64, 0x00, 0x00, 0x00, 0x06,
+                    0x00, 0x00, 0x00, 0x01, 0x00, 0x60,
+                    ('M' as u8), ('T' as u8), ('r' as u8), ('k' as u8),
+                    0x00, 0x00, 0x00, 0x13, // Track length: 19
+                    0x80, 0x00,             // Delta time: 0, padded
+                    0x90, 0x3C, 0x40,       // NoteOn
+                    0x60,                   // Delta time: 96
+                    0x3C, 0x00,             // Omit status (NoteOn)
+                    0x00, 0xFF, 0x03,       // TrackName...
+                    0x80, 0x02, 0x48, 0x69, // ...with a padded length
+                    0x00, 0xFF, 0x2F, 0x00, // EndOfTrack
+                    0x00, 0x00, 0x00, 0x1A  // Junk
+                    ];
+
+    match parse_bytes(test_buf) {
+        Err(e) => {
+            assert!(e.kind == OverlongVarLen);
+            assert!(e.offset == 22);
+        }
+        Ok(_) => { assert!(false); }
+    }
+
+    match parse_bytes_lossless(test_buf) {
+        Ok(file) => {
+            assert!(file.trailing_bytes == ~[0x00, 0x00, 0x00, 0x1A]);
+            assert!(to_bytes(&file, Lossless) == Ok(test_buf.to_owned()));
+
+            // Renaming the track only re-encodes the TrackName; the padded delta time and the
+            // running status stay as they were.
+            let mut renamed = file.clone();
+            renamed.tracks[0].events[2].message = Meta(TrackName{ text : ~[0x59, 0x6F, 0x21] });
+            match to_bytes(&renamed, Lossless) {
+                Ok(bytes) => {
+                    assert!(bytes.slice(22, 30).to_owned() == test_buf.slice(22, 30).to_owned());
+                    assert!(bytes.slice(30, 38).to_owned() == ~[0x00, 0xFF, 0x03, 0x80, 0x03, 0x59, 0x6F, 0x21]);
+                }
+                Err(_) => { assert!(false); }
+            }
+
+            // Plain writing tidies all of that away: two bytes of padding and the junk go, and the
+            // status byte comes back.
+            match to_bytes(&file, Plain) {
+                Ok(bytes) => { assert!(bytes.len() == test_buf.len() - 5); }
+                Err(_) => { assert!(false); }
+            }
+        }
+        Err(_) => { assert!(false); }
+    }
+}
+
+#[test]
+fn test_parse_lossless_refuses_lossy_events() {
+    // The velocity has its top bit set, which the parser masks off.
+    let test_buf = [('M' as u8), ('T' as u8), ('r' as u8), ('k' as u8),
+        0x00, 0x00, 0x00, 0x04,
+        0x00, 0x90, 0x3C, 0xC0];
+    assert!(parse_track(test_buf, 0, false).is_ok());
+    match parse_track(test_buf, 0, true) {
+        Err(e) => {
+            assert!(e.kind == LossyEvent);
+            assert!(e.offset == 8);
+        }
+        Ok(_) => { assert!(false); }
+    }
+
+    // An edited delta time too big for its padding loses the padding rather than the value.
+    let mut event = MidiEvent{ delta_time : 0x200000,
+                               message : Meta(EndOfTrack),
+                               encoding : Some(EventEncoding{ delta_padding : 1,
+                                                              omit_status : false,
+                                                              length_padding : 0 }) };
+    let mut out = ~[];
+    assert!(write_event(&event, 0x00, Lossless, &mut out, 0).is_ok());
+    assert!(out == ~[0x81, 0x80, 0x80, 0x00, 0xFF, 0x2F, 0x00]);
+    event.delta_time = 1;
+    out = ~[];
+    assert!(write_event(&event, 0x00, Lossless, &mut out, 0).is_ok());
+    assert!(out == ~[0x80, 0x01, 0xFF, 0x2F, 0x00]);
+}
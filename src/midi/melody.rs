@@ -0,0 +1,261 @@
+/** Guessing which track has the melody.
+ *
+ * Nothing in a MIDI file says which track is the tune. But the tune usually looks a certain way:
+ * one note at a time, in a middling-to-high register, moving around enough to be interesting, on
+ * a lead sort of instrument, and quite often in a track called "Melody" or "Lead". `rank_tracks`
+ * scores every track on each of those and puts the likeliest first.
+ */
+
+use std::ascii::StrAsciiExt;
+use super::{MidiFile, MidiTrack, MultipleAsynchronous, ProgramChange, DRUM_CHANNEL};
+use super::notes::{Note, track_notes, Stack};
+use super::timing::TempoMap;
+#[cfg(test)]
+use super::{MidiEvent, MidiMessage, MultipleSynchronous, TicksPerQuarter, NoteOn, NoteOff, Meta,
+            TrackName};
+
+/// Words in a track name that give it away, one way or the other.
+static LEAD_WORDS : [&'static str, ..7] = ["melody", "lead", "vocal", "voice", "solo", "main", "theme"];
+static BACKING_WORDS : [&'static str, ..10] = ["bass", "drum", "perc", "pad", "chord", "accomp", "harmony",
+                                               "rhythm", "backing", "strings"];
+
+/// How likely a track is to be the melody, along with what went into it. Every part is between 0
+/// and 1, where 1 is as melodic as it gets, and so is `score`.
+#[deriving(Clone)]
+pub struct TrackScore {
+    /// Counting from 0.
+    track : uint,
+    score : f64,
+    /// How wide the tune ranges, and how high it sits.
+    pitch : f64,
+    /// Notes per second, somewhere between a drone and a drum roll.
+    density : f64,
+    /// How much of the time the track is playing one note rather than a chord.
+    monophony : f64,
+    /// What the General MIDI program says about the instrument.
+    program : f64,
+    /// What the track name says, if anything.
+    name : f64
+}
+
+/// Scores every track in `file`, likeliest melody first. Tracks without any notes, and tracks
+/// that are mostly drums, score 0. Ties keep track order.
+pub fn rank_tracks(file : &MidiFile) -> ~[TrackScore] {
+    let shared = TempoMap::new(file);
+    let mut ranked : ~[TrackScore] = ~[];
+    for (i, track) in file.tracks.iter().enumerate() {
+        let score = if file.header.file_format == MultipleAsynchronous {
+            score_track(i, track, &TempoMap::for_track(file.header.division, track))
+        } else {
+            score_track(i, track, &shared)
+        };
+        let mut at = ranked.len();
+        while at > 0 && ranked[at - 1].score < score.score {
+            at -= 1;
+        }
+        ranked.insert(at, score);
+    }
+    ranked
+}
+
+/// The track most likely to have the melody, counting from 0, or None if no track has any notes.
+pub fn melody_track(file : &MidiFile) -> Option<uint> {
+    let ranked = rank_tracks(file);
+    if ranked.is_empty() || ranked[0].score == 0.0 {
+        None
+    } else {
+        Some(ranked[0].track)
+    }
+}
+
+fn score_track(index : uint, track : &MidiTrack, map : &TempoMap) -> TrackScore {
+    let notes = track_notes(track, Stack).all();
+    let drums = notes.iter().filter(|n| n.channel == DRUM_CHANNEL).count();
+    if notes.is_empty() || drums * 2 > notes.len() {
+        return TrackScore{ track : index, score : 0.0, pitch : 0.0, density : 0.0, monophony : 0.0,
+                           program : 0.0, name : 0.0 };
+    }
+
+    let pitch = pitch_score(notes);
+    let density = density_score(notes, map);
+    let monophony = monophony_score(notes);
+    let program = program_score(track);
+    let name = name_score(track);
+    // Playing one note at a time matters most; the rest are hints.
+    let score = 0.3 * monophony + 0.2 * pitch + 0.2 * density + 0.15 * program + 0.15 * name;
+    TrackScore{ track : index, score : score, pitch : pitch, density : density, monophony : monophony,
+                program : program, name : name }
+}
+
+/// Tunes tend to cover somewhere between a fifth and two octaves, around or above middle C.
+fn pitch_score(notes : &[Note]) -> f64 {
+    let mut lowest = 127u8;
+    let mut highest = 0u8;
+    let mut total = 0.0;
+    for note in notes.iter() {
+        if note.key < lowest { lowest = note.key; }
+        if note.key > highest { highest = note.key; }
+        total += note.key as f64;
+    }
+    let range = (highest - lowest) as f64;
+    let range_score = if range < 7.0 {
+        (range + 1.0) / 8.0
+    } else if range > 24.0 {
+        24.0 / range
+    } else {
+        1.0
+    };
+
+    let mean = total / (notes.len() as f64);
+    let register_score = if mean >= 60.0 {
+        1.0
+    } else if mean <= 36.0 {
+        0.0
+    } else {
+        (mean - 36.0) / 24.0
+    };
+    (range_score + register_score) / 2.0
+}
+
+/// Somewhere between one and a half and eight notes a second, from the first note to the end of
+/// the last.
+fn density_score(notes : &[Note], map : &TempoMap) -> f64 {
+    let mut end = 0u64;
+    for note in notes.iter() {
+        if note.start + note.duration > end {
+            end = note.start + note.duration;
+        }
+    }
+    let micros = map.ticks_to_micros(end) - map.ticks_to_micros(notes[0].start);
+    if micros == 0 {
+        return 0.0;
+    }
+    let per_second = (notes.len() as f64) * 1000000.0 / (micros as f64);
+    if per_second < 1.5 {
+        per_second / 1.5
+    } else if per_second > 8.0 {
+        8.0 / per_second
+    } else {
+        1.0
+    }
+}
+
+/// Of the time anything's sounding, how much has just the one note.
+fn monophony_score(notes : &[Note]) -> f64 {
+    // Ends sort before starts on the same tick, so back to back notes don't count as overlapping.
+    let mut changes : ~[(u64, int)] = ~[];
+    for note in notes.iter() {
+        changes.push((note.start, 1));
+        changes.push((note.start + note.duration, -1));
+    }
+    changes.sort();
+
+    let (mut sounding, mut alone) = (0u64, 0u64);
+    let mut count = 0;
+    let mut last_tick = 0u64;
+    for &(tick, change) in changes.iter() {
+        if count >= 1 {
+            sounding += tick - last_tick;
+        }
+        if count == 1 {
+            alone += tick - last_tick;
+        }
+        count += change;
+        last_tick = tick;
+    }
+    if sounding == 0 {
+        0.0
+    } else {
+        (alone as f64) / (sounding as f64)
+    }
+}
+
+/// Leads, brass, reeds and pipes are melody instruments; basses, pads and sound effects aren't.
+/// The rest could go either way. So could a track without a ProgramChange.
+fn program_score(track : &MidiTrack) -> f64 {
+    for event in track.events.iter() {
+        match event.message {
+            ProgramChange { new_program : p, _ } => {
+                return match p {
+                    56..87 => 1.0,
+                    0..31 | 40..47 | 104..111 => 0.5,
+                    32..39 | 48..55 | 88..103 | 112..127 => 0.1,
+                    _ => 0.5
+                };
+            }
+            _ => {}
+        }
+    }
+    0.5
+}
+
+fn name_score(track : &MidiTrack) -> f64 {
+    let name = match track.name() {
+        Some(n) => n.to_ascii_lower(),
+        None => { return 0.5; }
+    };
+    if LEAD_WORDS.iter().any(|w| name.contains(*w)) {
+        1.0
+    } else if BACKING_WORDS.iter().any(|w| name.contains(*w)) {
+        0.0
+    } else {
+        0.5
+    }
+}
+
+
+// Tests!
+
+/// One note after another on `channel`, each an eighth note long, with `chord` stacked on top of
+/// each (0 for none).
+#[cfg(test)]
+fn line(channel : u8, keys : &[u8], chord : u8) -> ~[(u32, MidiMessage)] {
+    let mut events = ~[];
+    for &key in keys.iter() {
+        events.push((0, NoteOn{ channel : channel, key : key, velocity : 100 }));
+        if chord > 0 {
+            events.push((0, NoteOn{ channel : channel, key : key + chord, velocity : 100 }));
+        }
+        events.push((48, NoteOff{ channel : channel, key : key, velocity : 0 }));
+        if chord > 0 {
+            events.push((0, NoteOff{ channel : channel, key : key + chord, velocity : 0 }));
+        }
+    }
+    events
+}
+
+#[test]
+fn test_rank_tracks() {
+    let tune = [72, 74, 76, 77, 79, 77, 76, 74, 72, 67];
+    let file = MidiFile::from_messages(MultipleSynchronous, TicksPerQuarter(96),
+                                       ~[// A conductor track with nothing to play.
+                                         ~[(0, Meta(TrackName{ text : "Conductor".as_bytes().to_owned() }))],
+                                         line(1, [48, 48, 53, 53, 55, 55, 48, 48, 43, 43], 0),
+                                         line(2, [60, 60, 65, 65, 67, 67, 60, 60, 55, 55], 4),
+                                         line(0, tune, 0),
+                                         line(9, [36, 38, 36, 38, 36, 38, 36, 38, 36, 38], 0)]);
+    let ranked = rank_tracks(&file);
+    // The bass line is one note at a time too, so it beats the chords, but it sits too low to beat
+    // the tune.
+    assert!(ranked.map(|s| s.track) == ~[3, 1, 2, 0, 4]);
+    assert!(ranked[0].monophony == 1.0);
+    assert!(ranked[1].monophony == 1.0);
+    assert!(ranked[1].pitch < ranked[0].pitch);
+    assert!(ranked[2].monophony == 0.0);
+    assert!(ranked[3].score == 0.0 && ranked[4].score == 0.0);
+    assert!(melody_track(&file) == Some(3));
+
+    // A name and an instrument can outweigh a register.
+    let mut named = file.clone();
+    named.tracks[1].events.unshift(MidiEvent{ delta_time : 0, encoding : None,
+                                              message : Meta(TrackName{ text : "Lead".as_bytes().to_owned() }) });
+    named.tracks[1].events.unshift(MidiEvent{ delta_time : 0, encoding : None,
+                                              message : ProgramChange{ channel : 1, new_program : 80 } });
+    named.tracks[3].events.unshift(MidiEvent{ delta_time : 0, encoding : None,
+                                              message : ProgramChange{ channel : 0, new_program : 33 } });
+    assert!(melody_track(&named) == Some(1));
+
+    let silent = MidiFile::from_messages(MultipleSynchronous, TicksPerQuarter(96),
+                                         ~[~[], line(9, [36, 38], 0)]);
+    assert!(melody_track(&silent).is_none());
+}
@@ -0,0 +1,371 @@
+/** Notes, rather than the NoteOn and NoteOff messages that make them up.
+ *
+ * A file never says "this note lasts a beat". It says a key went down, and some time later that
+ * it came back up, with any number of other events in between. `track_notes` pairs those up.
+ * And a key coming up doesn't always end the note either, if a pedal is down, so
+ * `track_notes_with_pedals` can follow the sustain and sostenuto pedals too.
+ */
+
+use std::cmp::max;
+use std::vec::from_elem;
+use super::{MidiTrack, NoteOn, NoteOff, ControlChange};
+#[cfg(test)]
+use super::MidiMessage;
+
+/// A note with a beginning and an end. `start` and `duration` are in ticks, `start` counting from
+/// the beginning of the track. `release_velocity` comes from the NoteOff, if there was one; a
+/// NoteOn with velocity 0 doesn't have a release velocity to give.
+#[deriving(Clone, Eq)]
+pub struct Note {
+    channel : u8,
+    key : u8,
+    velocity : u8,
+    start : u64,
+    duration : u64,
+    release_velocity : Option<u8>
+}
+
+/// The notes in a track, in the order they started. `dangling` notes never got a NoteOff, so they
+/// end where the track does.
+#[deriving(Clone, Eq)]
+pub struct TrackNotes {
+    notes : ~[Note],
+    dangling : ~[Note]
+}
+
+/// What to do with a NoteOn for a key that's already sounding on the same channel.
+#[deriving(Clone, Eq)]
+pub enum Retrigger {
+    /// The new note ends the old one there and then.
+    CutOff,
+    /// Both notes sound, and the NoteOffs that follow end them oldest first.
+    Stack
+}
+
+impl TrackNotes {
+    /// Every note, dangling or not, in the order they started.
+    pub fn all(&self) -> ~[Note] {
+        let mut all = ~[];
+        let (mut i, mut j) = (0u, 0u);
+        while i < self.notes.len() || j < self.dangling.len() {
+            if j == self.dangling.len() || (i < self.notes.len() && self.notes[i].start <= self.dangling[j].start) {
+                all.push(self.notes[i]);
+                i += 1;
+            } else {
+                all.push(self.dangling[j]);
+                j += 1;
+            }
+        }
+        all
+    }
+}
+
+/// Which pedals `track_notes_with_pedals` pays attention to.
+#[deriving(Clone, Eq)]
+pub struct Pedals {
+    /// CC 64: while it's down, keys that are let go keep sounding until it comes up.
+    sustain : bool,
+    /// CC 66: keys that are down when it's pressed keep sounding until it comes up, but keys
+    /// struck after it don't.
+    sostenuto : bool,
+    /// CC 67: notes struck while it's down come out softer. Durations don't change.
+    soft : bool
+}
+
+pub static NO_PEDALS : Pedals = Pedals{ sustain : false, sostenuto : false, soft : false };
+pub static ALL_PEDALS : Pedals = Pedals{ sustain : true, sostenuto : true, soft : true };
+
+/// Pairs up the NoteOns and NoteOffs in a track. A NoteOn with velocity 0 counts as a NoteOff, as
+/// the spec allows, and a NoteOff for a key that isn't sounding is ignored. Pedals are ignored too;
+/// see `track_notes_with_pedals`.
+pub fn track_notes(track : &MidiTrack, retrigger : Retrigger) -> TrackNotes {
+    track_notes_with_pedals(track, retrigger, NO_PEDALS)
+}
+
+/// Like `track_notes`, but a note held on by a pedal lasts until the pedal lets it go, the way it
+/// would actually sound. Striking a key again cuts off the note a pedal was holding for it. Notes
+/// that are still held when the track ends last until then, but aren't dangling, since their keys
+/// did come up.
+pub fn track_notes_with_pedals(track : &MidiTrack, retrigger : Retrigger, pedals : Pedals) -> TrackNotes {
+    let mut pairing = Pairing{ notes : ~[], sounding : ~[], held : ~[], caught : ~[],
+                               sustain_down : from_elem(16, false),
+                               sostenuto_down : from_elem(16, false),
+                               soft_down : from_elem(16, false),
+                               retrigger : retrigger,
+                               pedals : pedals };
+    let mut now = 0u64;
+
+    for event in track.events.iter() {
+        now += event.delta_time as u64;
+        match event.message {
+            NoteOn { channel : c, key : k, velocity : v } if v > 0 => { pairing.key_down(c, k, v, now); }
+            NoteOn { channel : c, key : k, _ } => { pairing.key_up(c, k, None, now); }
+            NoteOff { channel : c, key : k, velocity : v } => { pairing.key_up(c, k, Some(v), now); }
+            ControlChange { channel : c, controller : 64, value : v } if pedals.sustain => {
+                pairing.sustain_down[c] = v >= 64;
+                pairing.let_go(c, now);
+            }
+            ControlChange { channel : c, controller : 66, value : v } if pedals.sostenuto => {
+                pairing.sostenuto(c, v >= 64, now);
+            }
+            ControlChange { channel : c, controller : 67, value : v } if pedals.soft => {
+                pairing.soft_down[c] = v >= 64;
+            }
+            _ => {}
+        }
+    }
+    pairing.finish(now)
+}
+
+/// The work in progress for `track_notes_with_pedals`. Notes get their place in `notes` when they
+/// start, and their index in there is how the rest refer to them.
+struct Pairing {
+    notes : ~[Note],
+    /// Notes whose keys are still down, oldest first.
+    sounding : ~[uint],
+    /// Notes whose keys are up but which a pedal is holding on to, with their release velocities.
+    held : ~[(uint, Option<u8>)],
+    /// Notes the sostenuto pedal caught when it went down.
+    caught : ~[uint],
+    sustain_down : ~[bool],
+    sostenuto_down : ~[bool],
+    soft_down : ~[bool],
+    retrigger : Retrigger,
+    pedals : Pedals
+}
+
+impl Pairing {
+    fn key_down(&mut self, channel : u8, key : u8, velocity : u8, now : u64) {
+        let mut i = 0;
+        while i < self.held.len() {
+            let (n, release_velocity) = self.held[i];
+            if self.notes[n].channel == channel && self.notes[n].key == key {
+                self.end(n, now, release_velocity);
+                self.held.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        if self.retrigger == CutOff {
+            match self.release(channel, key) {
+                Some(n) => { self.end(n, now, None); }
+                None => {}
+            }
+        }
+
+        // Una corda takes about a third off.
+        let velocity = if self.soft_down[channel] { max(1, ((velocity as uint) * 2 / 3) as u8) } else { velocity };
+        self.sounding.push(self.notes.len());
+        self.notes.push(Note{ channel : channel, key : key, velocity : velocity, start : now, duration : 0,
+                              release_velocity : None });
+    }
+
+    fn key_up(&mut self, channel : u8, key : u8, release_velocity : Option<u8>, now : u64) {
+        match self.release(channel, key) {
+            Some(n) if self.is_held(n) => { self.held.push((n, release_velocity)); }
+            Some(n) => { self.end(n, now, release_velocity); }
+            None => {}
+        }
+    }
+
+    fn sostenuto(&mut self, channel : u8, down : bool, now : u64) {
+        if down && !self.sostenuto_down[channel] {
+            // Whatever's sounding gets caught, whether its key is down or the sustain pedal has it.
+            for &n in self.sounding.iter().chain(self.held.iter().map(|&(n, _)| n)) {
+                if self.notes[n].channel == channel {
+                    self.caught.push(n);
+                }
+            }
+        }
+        self.sostenuto_down[channel] = down;
+        if !down {
+            {
+                let notes = &self.notes;
+                self.caught.retain(|&n| notes[n].channel != channel);
+            }
+            self.let_go(channel, now);
+        }
+    }
+
+    /// Ends every note on `channel` that was only waiting on a pedal that's no longer down.
+    fn let_go(&mut self, channel : u8, now : u64) {
+        let mut i = 0;
+        while i < self.held.len() {
+            let (n, release_velocity) = self.held[i];
+            if self.notes[n].channel == channel && !self.is_held(n) {
+                self.end(n, now, release_velocity);
+                self.held.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Whether a pedal would keep note `n` sounding if its key came up now.
+    fn is_held(&self, n : uint) -> bool {
+        let channel = self.notes[n].channel;
+        (self.pedals.sustain && self.sustain_down[channel]) || self.caught.contains(&n)
+    }
+
+    /// Takes the oldest sounding note with this channel and key off `sounding`, if there is one.
+    fn release(&mut self, channel : u8, key : u8) -> Option<uint> {
+        let mut found = None;
+        for (position, &n) in self.sounding.iter().enumerate() {
+            if self.notes[n].channel == channel && self.notes[n].key == key {
+                found = Some(position);
+                break;
+            }
+        }
+        found.map(|position| self.sounding.remove(position))
+    }
+
+    fn end(&mut self, n : uint, now : u64, release_velocity : Option<u8>) {
+        self.notes[n].duration = now - self.notes[n].start;
+        self.notes[n].release_velocity = release_velocity;
+    }
+
+    fn finish(mut self, now : u64) -> TrackNotes {
+        for &(n, release_velocity) in self.held.clone().iter() {
+            self.end(n, now, release_velocity);
+        }
+        // Whatever's left never ended, so it lasts until the end of the track.
+        let mut dangling_at = from_elem(self.notes.len(), false);
+        for &n in self.sounding.iter() {
+            self.notes[n].duration = now - self.notes[n].start;
+            dangling_at[n] = true;
+        }
+        let mut result = TrackNotes{ notes : ~[], dangling : ~[] };
+        for (n, note) in self.notes.move_iter().enumerate() {
+            if dangling_at[n] {
+                result.dangling.push(note);
+            } else {
+                result.notes.push(note);
+            }
+        }
+        result
+    }
+}
+
+
+// Tests!
+
+#[test]
+fn test_track_notes() {
+    let track = MidiTrack::from_messages(~[(0,  NoteOn  { channel : 0, key : 60, velocity : 100 }),
+                                           (96, NoteOff { channel : 0, key : 60, velocity : 40 }),
+                                           (0,  NoteOn  { channel : 1, key : 60, velocity : 90 }),
+                                           (48, NoteOn  { channel : 0, key : 64, velocity : 80 }),
+                                           (48, NoteOn  { channel : 1, key : 60, velocity : 0 }),
+                                           // Nothing's sounding on channel 2, so this does nothing.
+                                           (0,  NoteOff { channel : 2, key : 64, velocity : 0 }),
+                                           (48, NoteOff { channel : 0, key : 64, velocity : 0 })]);
+    let notes = track_notes(&track, Stack);
+    assert!(notes.dangling.is_empty());
+    assert!(notes.notes == ~[Note{ channel : 0, key : 60, velocity : 100, start : 0, duration : 96,
+                                   release_velocity : Some(40) },
+                             Note{ channel : 1, key : 60, velocity : 90, start : 96, duration : 96,
+                                   release_velocity : None },
+                             Note{ channel : 0, key : 64, velocity : 80, start : 144, duration : 96,
+                                   release_velocity : Some(0) }]);
+}
+
+#[test]
+fn test_track_notes_retrigger() {
+    // The same key struck twice before either is released.
+    let track = MidiTrack::from_messages(~[(0,  NoteOn  { channel : 0, key : 60, velocity : 100 }),
+                                           (48, NoteOn  { channel : 0, key : 60, velocity : 50 }),
+                                           (48, NoteOff { channel : 0, key : 60, velocity : 0 }),
+                                           (48, NoteOff { channel : 0, key : 60, velocity : 0 })]);
+
+    let stacked = track_notes(&track, Stack);
+    assert!(stacked.notes.len() == 2);
+    assert!(stacked.notes[0].duration == 96);
+    assert!(stacked.notes[1].start == 48);
+    assert!(stacked.notes[1].duration == 96);
+
+    // Cut off, the second NoteOn ends the first note, and the second NoteOff has nothing to end.
+    let cut = track_notes(&track, CutOff);
+    assert!(cut.notes.len() == 2);
+    assert!(cut.notes[0].duration == 48);
+    assert!(cut.notes[0].release_velocity.is_none());
+    assert!(cut.notes[1].start == 48);
+    assert!(cut.notes[1].duration == 48);
+}
+
+#[test]
+fn test_track_notes_dangling() {
+    let track = MidiTrack::from_messages(~[(0,  NoteOn  { channel : 0, key : 60, velocity : 100 }),
+                                           (24, NoteOn  { channel : 0, key : 62, velocity : 100 }),
+                                           (24, NoteOff { channel : 0, key : 62, velocity : 0 }),
+                                           (48, NoteOn  { channel : 0, key : 64, velocity : 100 }),
+                                           (96, NoteOff { channel : 0, key : 67, velocity : 0 })]);
+    let notes = track_notes(&track, Stack);
+    assert!(notes.notes.len() == 1);
+    assert!(notes.notes[0].key == 62);
+    assert!(notes.dangling.len() == 2);
+    assert!(notes.dangling[0].key == 60);
+    assert!(notes.dangling[0].duration == 192);
+    assert!(notes.dangling[1].key == 64);
+    assert!(notes.dangling[1].duration == 96);
+
+    let all = notes.all();
+    assert!(all.iter().map(|n| n.key).collect::<~[u8]>() == ~[60, 62, 64]);
+}
+
+#[cfg(test)]
+fn cc(controller : u8, value : u8) -> MidiMessage {
+    ControlChange { channel : 0, controller : controller, value : value }
+}
+
+#[test]
+fn test_track_notes_sustain() {
+    // C is let go under the pedal and rings on until it comes up. D is struck and let go after
+    // that, so it ends on time. E is struck twice under the pedal, and the second cuts the first.
+    let track = MidiTrack::from_messages(~[(0,  NoteOn  { channel : 0, key : 60, velocity : 100 }),
+                                           (0,  cc(64, 127)),
+                                           (48, NoteOff { channel : 0, key : 60, velocity : 0 }),
+                                           (0,  NoteOn  { channel : 0, key : 64, velocity : 100 }),
+                                           (12, NoteOff { channel : 0, key : 64, velocity : 0 }),
+                                           (12, NoteOn  { channel : 0, key : 64, velocity : 100 }),
+                                           (12, NoteOff { channel : 0, key : 64, velocity : 0 }),
+                                           (24, cc(64, 0)),
+                                           (0,  NoteOn  { channel : 0, key : 62, velocity : 100 }),
+                                           (48, NoteOff { channel : 0, key : 62, velocity : 0 })]);
+    let pedalled = track_notes_with_pedals(&track, Stack, ALL_PEDALS);
+    assert!(pedalled.dangling.is_empty());
+    assert!(pedalled.notes.iter().map(|n| (n.key, n.start, n.duration)).collect::<~[(u8, u64, u64)]>()
+            == ~[(60, 0, 108), (64, 48, 24), (64, 72, 36), (62, 108, 48)]);
+
+    // Without the pedal, C ends when its key comes up.
+    let plain = track_notes(&track, Stack);
+    assert!(plain.notes[0].duration == 48);
+    assert!(plain.notes[1].duration == 12);
+}
+
+#[test]
+fn test_track_notes_sostenuto_and_soft() {
+    // Sostenuto catches C, which is down when it's pressed, but not E, struck after it.
+    let track = MidiTrack::from_messages(~[(0,  NoteOn  { channel : 0, key : 60, velocity : 90 }),
+                                           (0,  cc(66, 127)),
+                                           (0,  cc(67, 127)),
+                                           (0,  NoteOn  { channel : 0, key : 64, velocity : 90 }),
+                                           (24, NoteOff { channel : 0, key : 60, velocity : 0 }),
+                                           (0,  NoteOff { channel : 0, key : 64, velocity : 0 }),
+                                           (24, cc(66, 0)),
+                                           // G gets caught next, and the pedal's still down when the
+                                           // track ends.
+                                           (0,  NoteOn  { channel : 0, key : 67, velocity : 90 }),
+                                           (0,  cc(66, 127)),
+                                           (24, NoteOff { channel : 0, key : 67, velocity : 30 }),
+                                           (24, cc(67, 0))]);
+    let pedalled = track_notes_with_pedals(&track, Stack, ALL_PEDALS);
+    assert!(pedalled.dangling.is_empty());
+    assert!(pedalled.notes.iter().map(|n| (n.key, n.duration, n.velocity)).collect::<~[(u8, u64, u8)]>()
+            == ~[(60, 48, 90), (64, 24, 60), (67, 48, 60)]);
+    assert!(pedalled.notes[2].release_velocity == Some(30));
+
+    // The soft pedal alone changes velocities and nothing else.
+    let soft = track_notes_with_pedals(&track, Stack, Pedals{ sustain : false, sostenuto : false, soft : true });
+    assert!(soft.notes.iter().map(|n| (n.duration, n.velocity)).collect::<~[(u64, u8)]>()
+            == ~[(24, 90), (24, 60), (24, 60)]);
+}
@@ -0,0 +1,173 @@
+// Tests for the external-facing definitions in lib.rs, mostly parse_file and parse_bytes. Some of
+// these read the files in `midis/`, so they expect to be run from the root of the repository (which
+// is where `make test` runs them).
+extern mod midi;
+use std::io::File;
+use std::path::Path;
+use midi::{parse_file, parse_file_lossless, parse_bytes, to_bytes, MidiFile, MidiError,
+           MultipleSynchronous, TicksPerQuarter, Truncated, Meta, SetTempo, EndOfTrack,
+           Plain, RunningStatus, Lossless};
+
+static LA_OVERWORLD : &'static str = "midis/la_overworld.mid";
+
+fn la_overworld() -> MidiFile {
+    match parse_file(LA_OVERWORLD) {
+        Ok(file) => file,
+        Err(e) => { fail!("Couldn't parse {}: {}", LA_OVERWORLD, e.to_str()); }
+    }
+}
+
+/// A header for a format 1 file with `num_tracks` tracks, at 96 ticks per quarter note.
+fn header(num_tracks : u8) -> ~[u8] {
+    ~[0x4D, 0x54, 0x68, 0x64, 0x00, 0x00, 0x00, 0x06,
+      0x00, 0x01,
+      0x00, num_tracks,
+      0x00, 0x60]
+}
+
+/// A track chunk holding one NoteOn and an EndOfTrack.
+fn small_track(key : u8) -> ~[u8] {
+    ~[('M' as u8), ('T' as u8), ('r' as u8), ('k' as u8),
+      0x00, 0x00, 0x00, 0x08,
+      0x00, 0x90, key, 0x40,
+      0x00, 0xFF, 0x2F, 0x00]
+}
+
+#[test]
+fn test_la_overworld_header() {
+    let file = la_overworld();
+    match file.header.file_format {
+        MultipleSynchronous => assert!(true),
+        _ => assert!(false)
+    }
+    assert!(file.header.num_tracks == 8);
+    assert!(file.header.division == TicksPerQuarter(48));
+}
+
+#[test]
+fn test_la_overworld_tracks() {
+    let file = la_overworld();
+    let lengths = [239u32, 886, 2194, 3760, 3446, 3449, 1413, 207];
+    let event_counts = [14u, 70, 719, 1240, 1139, 1140, 455, 59];
+    let names = ["System", "Square 1", "Square 2", "Bass 1-1", "Bass 1-2", "Saw 1", "Saw 2"];
+
+    assert!(file.tracks.len() == 8);
+    for i in range(0, 8u) {
+        let track = &file.tracks[i];
+        assert!(track.track_length == lengths[i]);
+        assert!(track.events.len() == event_counts[i]);
+        assert!(track.events[track.events.len() - 1].message == Meta(EndOfTrack));
+        if i > 0 {
+            assert!(track.name() == Some(names[i - 1].to_owned()));
+        }
+    }
+    assert!(file.tracks[0].events[0].message == Meta(SetTempo{ tempo : 397350 }));
+}
+
+#[test]
+fn test_parse_bytes_multiple_tracks() {
+    let mut buf = header(3);
+    buf.push_all(small_track(60));
+    buf.push_all(small_track(62));
+    buf.push_all(small_track(64));
+    match parse_bytes(buf) {
+        Ok(file) => {
+            assert!(file.tracks.len() == 3);
+            assert!(file.tracks[2].events.len() == 2);
+        }
+        Err(e) => { fail!(e.to_str()); }
+    }
+}
+
+#[test]
+fn test_parse_bytes_skips_unknown_chunks() {
+    let mut buf = header(2);
+    buf.push_all(small_track(60));
+    buf.push_all([('X' as u8), ('F' as u8), ('I' as u8), ('H' as u8),
+                  0x00, 0x00, 0x00, 0x03, 0x01, 0x02, 0x03]);
+    buf.push_all(small_track(62));
+    match parse_bytes(buf) {
+        Ok(file) => {
+            assert!(file.tracks.len() == 2);
+            assert!(file.unknown_chunks.len() == 1);
+            assert!(file.unknown_chunks[0].id == "XFIH".as_bytes().to_owned());
+            assert!(file.unknown_chunks[0].data == ~[0x01, 0x02, 0x03]);
+            assert!(file.unknown_chunks[0].after_track == 1);
+        }
+        Err(e) => { fail!(e.to_str()); }
+    }
+}
+
+#[test]
+fn test_parse_bytes_ignores_trailing_junk() {
+    let mut buf = header(1);
+    buf.push_all(small_track(60));
+    buf.push_all([0x00, 0x00, 0x1A]);
+    match parse_bytes(buf) {
+        Ok(file) => { assert!(file.tracks.len() == 1); }
+        Err(e) => { fail!(e.to_str()); }
+    }
+}
+
+#[test]
+fn test_parse_bytes_missing_track() {
+    // The header promises two tracks, but the second one isn't a track.
+    let mut buf = header(2);
+    buf.push_all(small_track(60));
+    let mut bad = small_track(62);
+    bad[3] = 'x' as u8;
+    buf.push_all(bad);
+    match parse_bytes(buf) {
+        Err(MidiError{ kind : Truncated, offset : o, track : Some(t) }) => {
+            assert!(o == 46);
+            assert!(t == 1);
+        }
+        _ => { assert!(false); }
+    }
+}
+
+#[test]
+fn test_to_bytes_keeps_unknown_chunks() {
+    let mut buf = header(2);
+    buf.push_all(small_track(60));
+    buf.push_all([('X' as u8), ('F' as u8), ('I' as u8), ('H' as u8),
+                  0x00, 0x00, 0x00, 0x01, 0x07]);
+    buf.push_all(small_track(62));
+    match parse_bytes(buf) {
+        Ok(file) => { assert!(to_bytes(&file, RunningStatus) == Ok(buf.clone())); }
+        Err(e) => { fail!(e.to_str()); }
+    }
+}
+
+#[test]
+fn test_la_overworld_write_and_read_back() {
+    let file = la_overworld();
+    for &style in [Plain, RunningStatus].iter() {
+        let bytes = match to_bytes(&file, style) {
+            Ok(b) => b,
+            Err(e) => { fail!(e.to_str()); }
+        };
+        let back = match parse_bytes(bytes) {
+            Ok(f) => f,
+            Err(e) => { fail!(e.to_str()); }
+        };
+        assert!(back.header.num_tracks == file.header.num_tracks);
+        assert!(back.tracks.len() == file.tracks.len());
+        for (mine, theirs) in back.tracks.iter().zip(file.tracks.iter()) {
+            assert!(mine.events.len() == theirs.events.len());
+            for (a, b) in mine.events.iter().zip(theirs.events.iter()) {
+                assert!(a.delta_time == b.delta_time);
+                assert!(a.message == b.message);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_la_overworld_lossless() {
+    let original = File::open(&Path::new(LA_OVERWORLD)).read_to_end();
+    match parse_file_lossless(LA_OVERWORLD) {
+        Ok(file) => { assert!(to_bytes(&file, Lossless) == Ok(original)); }
+        Err(e) => { fail!(e.to_str()); }
+    }
+}
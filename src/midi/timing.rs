@@ -0,0 +1,259 @@
+/** Where events fall in real time, rather than in ticks.
+ *
+ * Delta times count ticks since the previous event in the same track, and how long a tick lasts
+ * depends on the tempo (see Division), which SetTempo events can change whenever they like. A
+ * TempoMap gathers those changes up so any tick can be turned into microseconds, and
+ * `timed_events` walks a whole file in order with both worked out.
+ */
+
+use std::vec::from_elem;
+use super::{MidiFile, MidiTrack, MidiEvent, Division, MultipleAsynchronous, Meta, SetTempo};
+#[cfg(test)]
+use super::{MidiMessage, SingleTrack, MultipleSynchronous, TicksPerQuarter, Smpte, NoteOn, NoteOff,
+            EndOfTrack};
+
+/// Microseconds per quarter note until a SetTempo says otherwise, which is 120 BPM.
+pub static DEFAULT_TEMPO : u32 = 500000;
+
+/// From `tick` on, a quarter note lasts `tempo` microseconds. `micros` is when `tick` happens.
+#[deriving(Clone, Eq)]
+pub struct TempoChange {
+    tick : u64,
+    micros : u64,
+    tempo : u32
+}
+
+/// Every tempo change in a piece, in order, starting with the default tempo at tick 0.
+#[deriving(Clone)]
+pub struct TempoMap {
+    division : Division,
+    changes : ~[TempoChange]
+}
+
+/// An event and when it happens, counted from the start of the piece it's in. `track` counts
+/// from 0.
+pub struct TimedEvent<'a> {
+    track : uint,
+    tick : u64,
+    micros : u64,
+    event : &'a MidiEvent
+}
+
+/// Walks every event in a file in the order they happen. See `timed_events`.
+pub struct TimedEvents<'a> {
+    priv file : &'a MidiFile,
+    /// One map shared by every track, or for format 2 files, one per track.
+    priv maps : ~[TempoMap],
+    /// The index of the next event to hand out in each track.
+    priv positions : ~[uint],
+    /// The tick of the last event handed out in each track.
+    priv ticks : ~[u64]
+}
+
+impl TempoMap {
+    /// The tempo map for a file. In format 0 and 1 files the tracks all play together, so a
+    /// SetTempo in any of them applies to the lot (by convention they're all in the first track).
+    /// Format 2 tracks are separate pieces, so use `for_track` for those.
+    pub fn new(file : &MidiFile) -> TempoMap {
+        let mut tempos = ~[];
+        for track in file.tracks.iter() {
+            for &(tick, tempo) in tempo_events(track).iter() {
+                // Keep them in order of tick, and for the same tick, in track order.
+                let mut i = tempos.len();
+                while i > 0 {
+                    let (previous, _) = tempos[i - 1];
+                    if previous <= tick {
+                        break;
+                    }
+                    i -= 1;
+                }
+                tempos.insert(i, (tick, tempo));
+            }
+        }
+        TempoMap::from_tempos(file.header.division, tempos)
+    }
+
+    /// The tempo map for a single track, going by its own SetTempo events and nothing else.
+    pub fn for_track(division : Division, track : &MidiTrack) -> TempoMap {
+        TempoMap::from_tempos(division, tempo_events(track))
+    }
+
+    /// When `tick` happens, in microseconds from the start.
+    pub fn ticks_to_micros(&self, tick : u64) -> u64 {
+        let change = self.change_at(tick);
+        change.micros + self.division.ticks_to_micros(tick - change.tick, change.tempo)
+    }
+
+    /// The tempo in effect at `tick`, in microseconds per quarter note.
+    pub fn tempo_at(&self, tick : u64) -> u32 {
+        self.change_at(tick).tempo
+    }
+
+    /// `tempos` has to be in order of tick. When two land on the same tick, the later one wins.
+    fn from_tempos(division : Division, tempos : &[(u64, u32)]) -> TempoMap {
+        let mut changes = ~[TempoChange{ tick : 0, micros : 0, tempo : DEFAULT_TEMPO }];
+        for &(tick, tempo) in tempos.iter() {
+            let last_index = changes.len() - 1;
+            let last = changes[last_index];
+            if tick == last.tick {
+                changes[last_index].tempo = tempo;
+            } else {
+                let micros = last.micros + division.ticks_to_micros(tick - last.tick, last.tempo);
+                changes.push(TempoChange{ tick : tick, micros : micros, tempo : tempo });
+            }
+        }
+        TempoMap{ division : division, changes : changes }
+    }
+
+    /// The last change at or before `tick`. There's always one, since the first is at tick 0.
+    fn change_at(&self, tick : u64) -> TempoChange {
+        let mut low = 0u;
+        let mut high = self.changes.len();
+        while high - low > 1 {
+            let middle = (low + high) / 2;
+            if self.changes[middle].tick <= tick {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+        self.changes[low]
+    }
+}
+
+/// Every event in `file` along with its track, absolute tick and absolute time. Events on the same
+/// tick come out in track order, and keep their order within a track. Format 2 files are walked one
+/// track after another instead, each timed from zero by its own tempo events.
+pub fn timed_events<'a>(file : &'a MidiFile) -> TimedEvents<'a> {
+    let num_tracks = file.tracks.len();
+    let maps = if file.header.file_format == MultipleAsynchronous {
+        file.tracks.iter().map(|t| TempoMap::for_track(file.header.division, t)).collect::<~[TempoMap]>()
+    } else {
+        ~[TempoMap::new(file)]
+    };
+    TimedEvents{ file : file,
+                 maps : maps,
+                 positions : from_elem(num_tracks, 0u),
+                 ticks : from_elem(num_tracks, 0u64) }
+}
+
+impl<'a> TimedEvents<'a> {
+    /// The tick of the next event in track `i`, if it has any left.
+    fn upcoming(&self, i : uint) -> Option<u64> {
+        let events = &self.file.tracks[i].events;
+        if self.positions[i] < events.len() {
+            Some(self.ticks[i] + (events[self.positions[i]].delta_time as u64))
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> Iterator<TimedEvent<'a>> for TimedEvents<'a> {
+    fn next(&mut self) -> Option<TimedEvent<'a>> {
+        let one_at_a_time = self.file.header.file_format == MultipleAsynchronous;
+        let mut best : Option<(uint, u64)> = None;
+        for i in range(0, self.file.tracks.len()) {
+            match (self.upcoming(i), best) {
+                (Some(tick), None) => { best = Some((i, tick)); }
+                (Some(tick), Some((_, best_tick))) if tick < best_tick && !one_at_a_time => {
+                    best = Some((i, tick));
+                }
+                _ => {}
+            }
+        }
+
+        match best {
+            None => None,
+            Some((i, tick)) => {
+                let file = self.file;
+                let event = &file.tracks[i].events[self.positions[i]];
+                self.positions[i] += 1;
+                self.ticks[i] = tick;
+                let map = if self.maps.len() == 1 { &self.maps[0] } else { &self.maps[i] };
+                Some(TimedEvent{ track : i, tick : tick, micros : map.ticks_to_micros(tick), event : event })
+            }
+        }
+    }
+}
+
+/// The SetTempo events in a track, as (absolute tick, tempo).
+fn tempo_events(track : &MidiTrack) -> ~[(u64, u32)] {
+    let mut tempos = ~[];
+    let mut now = 0u64;
+    for event in track.events.iter() {
+        now += event.delta_time as u64;
+        match event.message {
+            Meta(SetTempo{ tempo : t }) => { tempos.push((now, t)); }
+            _ => {}
+        }
+    }
+    tempos
+}
+
+
+// Tests!
+
+#[test]
+fn test_tempo_map() {
+    // Half speed at beat 2, and then (from the second track) half speed again at beat 3.
+    let file = MidiFile::from_messages(MultipleSynchronous, TicksPerQuarter(96),
+                                       ~[~[(192, Meta(SetTempo{ tempo : 250000 }))],
+                                         ~[(288, Meta(SetTempo{ tempo : 1000000 }))]]);
+    let map = TempoMap::new(&file);
+    assert!(map.ticks_to_micros(0) == 0);
+    assert!(map.ticks_to_micros(96) == 500000);
+    assert!(map.ticks_to_micros(192) == 1000000);
+    assert!(map.ticks_to_micros(240) == 1125000);
+    assert!(map.ticks_to_micros(288) == 1250000);
+    assert!(map.ticks_to_micros(384) == 2250000);
+    assert!(map.tempo_at(100) == 500000);
+    assert!(map.tempo_at(200) == 250000);
+    assert!(map.tempo_at(300) == 1000000);
+
+    // Only the first track's tempo change counts when it's taken alone.
+    let first = TempoMap::for_track(TicksPerQuarter(96), &file.tracks[0]);
+    assert!(first.ticks_to_micros(384) == 1500000);
+}
+
+#[test]
+fn test_tempo_map_same_tick() {
+    // Two tempos at once: the last one wins, even when it's in a later track.
+    let file = MidiFile::from_messages(MultipleSynchronous, TicksPerQuarter(96),
+                                       ~[~[(0, Meta(SetTempo{ tempo : 250000 }))],
+                                         ~[(0, Meta(SetTempo{ tempo : 1000000 }))]]);
+    let map = TempoMap::new(&file);
+    assert!(map.changes.len() == 1);
+    assert!(map.ticks_to_micros(96) == 1000000);
+
+    // SMPTE ticks don't care about tempo at all.
+    let smpte = MidiFile::from_messages(SingleTrack, Smpte{ frames_per_second : 25, ticks_per_frame : 40 },
+                                        ~[~[(0, Meta(SetTempo{ tempo : 1000000 }))]]);
+    assert!(TempoMap::new(&smpte).ticks_to_micros(250) == 250000);
+}
+
+#[test]
+fn test_timed_events() {
+    let file = MidiFile::from_messages(MultipleSynchronous, TicksPerQuarter(96),
+                                       ~[~[(0,  Meta(SetTempo{ tempo : 250000 })),
+                                           (96, Meta(EndOfTrack))],
+                                         ~[(48, NoteOn{ channel : 0, key : 60, velocity : 100 }),
+                                           (48, NoteOff{ channel : 0, key : 60, velocity : 0 }),
+                                           (0,  Meta(EndOfTrack))]]);
+    let timed = timed_events(&file).map(|e| (e.track, e.tick, e.micros)).collect::<~[(uint, u64, u64)]>();
+    assert!(timed == ~[(0, 0, 0), (1, 48, 125000), (0, 96, 250000), (1, 96, 250000), (1, 96, 250000)]);
+
+    let messages = timed_events(&file).map(|e| e.event.message.clone()).collect::<~[MidiMessage]>();
+    assert!(messages[3] == NoteOff{ channel : 0, key : 60, velocity : 0 });
+}
+
+#[test]
+fn test_timed_events_format_two() {
+    // Separate pieces: each track starts at zero, with its own tempo.
+    let file = MidiFile::from_messages(MultipleAsynchronous, TicksPerQuarter(96),
+                                       ~[~[(96, Meta(EndOfTrack))],
+                                         ~[(0,  Meta(SetTempo{ tempo : 1000000 })),
+                                           (96, Meta(EndOfTrack))]]);
+    let timed = timed_events(&file).map(|e| (e.track, e.tick, e.micros)).collect::<~[(uint, u64, u64)]>();
+    assert!(timed == ~[(0, 96, 500000), (1, 0, 0), (1, 96, 1000000)]);
+}